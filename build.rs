@@ -14,8 +14,14 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use std::env;
+use std::path::Path;
 use std::process::Command;
 
+use syntect::dumps::{dump_to_file, dump_to_uncompressed_file};
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxSet;
+
 fn main() {
     let output = Command::new("git")
         .args(["rev-parse", "HEAD"])
@@ -24,4 +30,15 @@ fn main() {
     let git_hash = String::from_utf8(output.stdout).unwrap();
     println!("cargo:rustc-env=GIT_HASH={}", git_hash);
     println!("cargo:rerun-if-changed=src/schema2.graphql,templates/main.css");
+
+    // syntect's bundled syntaxes are compressed and have to be inflated every time
+    // they are loaded, dumping them uncompressed makes startup cheaper
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let out_dir = Path::new(&out_dir);
+    dump_to_uncompressed_file(
+        &SyntaxSet::load_defaults_newlines(),
+        out_dir.join("syntaxes.packdump"),
+    )
+    .unwrap();
+    dump_to_file(&ThemeSet::load_defaults(), out_dir.join("themes.themedump")).unwrap();
 }
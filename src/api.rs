@@ -0,0 +1,556 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! JSON API for posts
+//!
+//! Posts are normalised into documents that don't depend on the shape of
+//! Medium's API. Within `/api/v1`, fields are only ever added; removing or
+//! changing a field requires a new version of the API.
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::code_embed::CodeEmbed;
+use crate::data::{FetchError, GistContent, Paragraph, PostResp};
+use crate::proxy::StringUtils;
+use crate::render_html::SourcegraphQuery;
+use crate::resolve::{absolute, get_post_id, rewrite_link};
+use crate::{AppData, Data};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PostDocument {
+    pub id: String,
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub slug: String,
+    /// URL of the post on this instance
+    pub url: String,
+    pub medium_url: Option<String>,
+    /// Set when the post was first published elsewhere
+    pub canonical_url: Option<String>,
+    /// RFC 3339
+    pub created_at: String,
+    /// Minutes
+    pub reading_time: f64,
+    pub author: Author,
+    pub publication: Option<Publication>,
+    pub tags: Vec<Tag>,
+    pub preview_image: Option<String>,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Author {
+    pub id: String,
+    pub name: String,
+    pub username: String,
+    /// URL of the author's page on this instance
+    pub url: String,
+    pub image: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Publication {
+    pub id: String,
+    pub name: String,
+    pub image: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tag {
+    pub id: String,
+    pub title: String,
+}
+
+/// Blocks are tagged with their `type`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    Heading {
+        /// 1 to 6
+        level: u8,
+        spans: Vec<Span>,
+    },
+    Paragraph {
+        spans: Vec<Span>,
+    },
+    Quote {
+        /// Pull quotes repeat text of the post for emphasis
+        pull: bool,
+        spans: Vec<Span>,
+    },
+    /// Consecutive list items are grouped into one list
+    List {
+        ordered: bool,
+        items: Vec<Vec<Span>>,
+    },
+    Image {
+        /// Medium's ID of the image
+        id: String,
+        url: String,
+        width: Option<i64>,
+        height: Option<i64>,
+        caption: Vec<Span>,
+    },
+    Code {
+        /// Language of the code, detected when the author didn't set one. `None`
+        /// for plain text.
+        language: Option<String>,
+        code: String,
+    },
+    /// Gists, GitHub files, GitLab snippets and Pastebin pastes
+    CodeEmbed {
+        url: String,
+        host: String,
+        /// `None` when the code couldn't be fetched
+        files: Option<Vec<CodeFile>>,
+    },
+    /// Third-party embeds, like videos and tweets
+    Embed {
+        url: String,
+        title: Option<String>,
+    },
+    /// Link cards to other pages
+    Link {
+        url: String,
+        title: String,
+        description: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CodeFile {
+    pub name: String,
+    pub language: Option<String>,
+    pub content: String,
+}
+
+/// Text with the same formatting
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Span {
+    pub text: String,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub marks: Vec<Mark>,
+    /// Links to Medium are resolved to this instance
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub link: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mark {
+    Strong,
+    Em,
+    Code,
+}
+
+pub mod routes {
+    pub struct Api {
+        pub post: &'static str,
+    }
+
+    impl Api {
+        pub const fn new() -> Self {
+            Self {
+                post: "/api/v1/post/{id}",
+            }
+        }
+
+        pub fn get_post(&self, id: &str) -> String {
+            self.post.replace("{id}", id)
+        }
+    }
+}
+
+/// Post as a [PostDocument]. `id` is a post ID or URL.
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.api.post")]
+async fn post_document(path: web::Path<String>, data: AppData) -> impl Responder {
+    match get_post_id(&path) {
+        Some(id) => match get_document(&id, &data).await {
+            Ok(document) => HttpResponse::Ok().json(document),
+            Err(e) => crate::proxy::post_error(e),
+        },
+        None => HttpResponse::BadRequest().body("Invalid post ID"),
+    }
+}
+
+/// Fetch and normalise a post, along with its code embeds
+pub async fn get_document(id: &str, data: &Data) -> Result<PostDocument, FetchError> {
+    let post = data.get_post(id).await?;
+    let futs: Vec<_> = crate::proxy::code_embed_srcs(&post)
+        .into_iter()
+        .map(|src| data.get_code_embed_source(src))
+        .collect();
+    let code_embeds = join_all(futs).await;
+    Ok(document(id, &post, &code_embeds))
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(post_document);
+}
+
+/// Normalise a post. `code_embeds` are unrendered, see
+/// [crate::data::Data::get_code_embed_source].
+pub fn document(
+    id: &str,
+    post: &PostResp,
+    code_embeds: &[(String, Option<GistContent>)],
+) -> PostDocument {
+    let proxy = &crate::V1_API_ROUTES.proxy;
+    let asset = |id: &str| absolute(&proxy.get_medium_asset(id));
+    PostDocument {
+        id: id.to_owned(),
+        title: post.title.clone(),
+        subtitle: post.preview_content.as_ref().map(|p| p.subtitle.clone()),
+        slug: post.unique_slug.clone(),
+        url: absolute(&proxy.get_page(&post.creator.username, &post.unique_slug)),
+        medium_url: post.medium_url.clone(),
+        canonical_url: post.canonical_url.clone().filter(|url| !url.is_empty()),
+        created_at: Utc
+            .timestamp_millis_opt(post.created_at)
+            .unwrap()
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+        reading_time: post.reading_time,
+        author: Author {
+            id: post.creator.id.clone(),
+            name: post.creator.name.clone(),
+            username: post.creator.username.clone(),
+            url: absolute(&proxy.get_author(&post.creator.username)),
+            image: asset(&post.creator.image_id),
+        },
+        publication: post.collection.as_ref().map(|c| Publication {
+            id: c.id.clone(),
+            name: c.name.clone(),
+            image: c.avatar.as_ref().and_then(|a| a.id.as_deref()).map(asset),
+        }),
+        tags: post
+            .tags
+            .iter()
+            .flatten()
+            .map(|tag| Tag {
+                id: tag.id.clone(),
+                title: tag.display_title.clone().unwrap_or_else(|| tag.id.clone()),
+            })
+            .collect(),
+        preview_image: post
+            .preview_image
+            .as_ref()
+            .and_then(|i| i.id.as_deref())
+            .map(asset),
+        blocks: blocks(&post.content.body_model.paragraphs, code_embeds),
+    }
+}
+
+fn blocks(paragraphs: &[Paragraph], code_embeds: &[(String, Option<GistContent>)]) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::with_capacity(paragraphs.len());
+    // consecutive PRE paragraphs are merged into one code block, like in HTML
+    let mut code_block_end = 0;
+    for (pindex, p) in paragraphs.iter().enumerate() {
+        // the title, which is part of the metadata
+        if p.type_ == "H3" && pindex == 0 {
+            continue;
+        }
+        if pindex < code_block_end {
+            continue;
+        }
+
+        let block = match p.type_.as_str() {
+            type_ @ ("H1" | "H2" | "H3" | "H4" | "H5" | "H6") => Block::Heading {
+                level: type_[1..].parse().unwrap(),
+                spans: spans(p),
+            },
+            "BQ" | "PQ" => Block::Quote {
+                pull: p.type_ == "PQ",
+                spans: spans(p),
+            },
+            type_ @ ("ULI" | "OLI") => {
+                let ordered = type_ == "OLI";
+                if let Some(Block::List {
+                    ordered: prev,
+                    items,
+                }) = blocks.last_mut()
+                {
+                    // blocks can only be lists if the previous paragraph was a list item
+                    if *prev == ordered && paragraphs[pindex - 1].type_ == type_ {
+                        items.push(spans(p));
+                        continue;
+                    }
+                }
+                Block::List {
+                    ordered,
+                    items: vec![spans(p)],
+                }
+            }
+            "IMG" => {
+                let metadata = p.metadata.as_ref().unwrap();
+                Block::Image {
+                    id: metadata.id.clone(),
+                    url: absolute(&crate::V1_API_ROUTES.proxy.get_medium_asset(&metadata.id)),
+                    width: metadata.original_width,
+                    height: metadata.original_height,
+                    caption: spans(p),
+                }
+            }
+            "PRE" => {
+                code_block_end = pindex
+                    + paragraphs[pindex..]
+                        .iter()
+                        .take_while(|p| p.type_ == "PRE")
+                        .count();
+                let code = paragraphs[pindex..code_block_end]
+                    .iter()
+                    .fold(String::default(), |code, p| code + &p.text + "\n");
+                let lang = p
+                    .code_block_metadata
+                    .as_ref()
+                    .and_then(|m| m.lang.as_deref());
+                Block::Code {
+                    language: SourcegraphQuery {
+                        filepath: "",
+                        code: &code,
+                        lang,
+                    }
+                    .language(),
+                    code,
+                }
+            }
+            "IFRAME" => {
+                let media = p.iframe.as_ref().unwrap().media_resource.as_ref().unwrap();
+                code_embed(&media.href, code_embeds).unwrap_or_else(|| Block::Embed {
+                    url: media.href.clone(),
+                    title: media.title.clone().filter(|t| !t.is_empty()),
+                })
+            }
+            "MIXTAPE_EMBED" => match &p.mixtape_metadata {
+                Some(mixtape) => {
+                    let resource = mixtape.media_resource.as_ref();
+                    Block::Link {
+                        url: absolute(&rewrite_link(&mixtape.href)),
+                        title: resource.and_then(|r| r.title.clone()).unwrap_or_else(|| {
+                            // older posts don't have the resource, the title is the first line
+                            p.text.lines().next().unwrap_or_default().to_owned()
+                        }),
+                        description: resource.and_then(|r| r.description.clone()),
+                    }
+                }
+                None => Block::Paragraph { spans: spans(p) },
+            },
+            _ => Block::Paragraph { spans: spans(p) },
+        };
+        blocks.push(block);
+    }
+    blocks
+}
+
+fn code_embed(src: &str, code_embeds: &[(String, Option<GistContent>)]) -> Option<Block> {
+    let code = CodeEmbed::classify(src)?;
+    let content = code_embeds
+        .iter()
+        .find(|(url, _)| url == src)
+        .and_then(|(_, content)| content.as_ref());
+    Some(Block::CodeEmbed {
+        url: src.to_owned(),
+        host: code.host().to_owned(),
+        files: content.map(|content| {
+            content
+                .files
+                .iter()
+                .map(|file| CodeFile {
+                    name: file.file_name.clone(),
+                    language: SourcegraphQuery {
+                        filepath: &file.file_name,
+                        code: &file.content,
+                        lang: None,
+                    }
+                    .language(),
+                    content: file.content.clone(),
+                })
+                .collect()
+        }),
+    })
+}
+
+/// Split the text of a paragraph at markup boundaries, so that each span has
+/// the same formatting. Overlapping markups are supported.
+fn spans(p: &Paragraph) -> Vec<Span> {
+    let len = p.text.chars().count();
+    let mut bounds: Vec<usize> = vec![0, len];
+    for m in p.markups.iter() {
+        bounds.push((m.start as usize).min(len));
+        bounds.push((m.end as usize).min(len));
+    }
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut spans: Vec<Span> = Vec::default();
+    for range in bounds.windows(2) {
+        let (start, end) = (range[0], range[1]);
+        let mut span = Span {
+            text: p.text.slice(start..end).to_owned(),
+            ..Span::default()
+        };
+        for m in p
+            .markups
+            .iter()
+            .filter(|m| m.start as usize <= start && end <= m.end as usize)
+        {
+            match m.type_.as_str() {
+                "STRONG" => span.marks.push(Mark::Strong),
+                "EM" => span.marks.push(Mark::Em),
+                "CODE" => span.marks.push(Mark::Code),
+                "A" => {
+                    span.link = match (m.anchor_type.as_deref(), &m.href, &m.user_id) {
+                        (Some("LINK"), Some(href), _) => Some(absolute(&rewrite_link(href))),
+                        (Some("USER"), _, Some(user_id)) => Some(absolute(
+                            &crate::V1_API_ROUTES.proxy.get_by_user_id(user_id),
+                        )),
+                        _ => span.link,
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // spans with the same formatting are merged
+        match spans.last_mut() {
+            Some(last) if last.marks == span.marks && last.link == span.link => {
+                last.text += &span.text;
+            }
+            _ => spans.push(span),
+        }
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+
+    use super::*;
+    use crate::data::GistFile;
+    use crate::routes::services;
+    use crate::{SETTINGS, V1_API_ROUTES};
+
+    #[actix_rt::test]
+    async fn document_works() {
+        let post = crate::proxy::tests::post();
+        let doc = document("7158b1cdd50c", &post, &[]);
+        let base = SETTINGS.server.get_base_url();
+        assert_eq!(doc.created_at, "2017-09-27T17:05:11Z");
+        assert_eq!(doc.canonical_url, None);
+        assert_eq!(doc.tags.len(), 3);
+        assert_eq!(
+            doc.url,
+            format!("{base}/tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c")
+        );
+
+        let json = serde_json::to_value(&doc).unwrap();
+        let blocks = json["blocks"].as_array().unwrap();
+        // the title isn't a block
+        assert_eq!(blocks[0]["type"], "image");
+        assert_eq!(
+            blocks[1]["spans"][1],
+            serde_json::json!({"text": "two", "link": "http://www.1024cores.net/"})
+        );
+        assert_eq!(
+            blocks[1]["spans"][3]["link"],
+            format!("{base}/utils/user/5145b6327de1")
+        );
+
+        let list = blocks.iter().find(|b| b["type"] == "list").unwrap();
+        assert_eq!(list["ordered"], true);
+        assert_eq!(list["items"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            list["items"][1][1],
+            serde_json::json!({"text": "stack.head", "marks": ["code"]})
+        );
+
+        let code = blocks.iter().find(|b| b["type"] == "code").unwrap();
+        assert_eq!(code["language"], "rust");
+        assert_eq!(code["code"].as_str().unwrap().lines().count(), 3);
+
+        let link = blocks.iter().find(|b| b["type"] == "link").unwrap();
+        assert_eq!(link["title"], "Big Data, Small Effort");
+        assert_eq!(link["url"], format!("{base}/utils/post/b62607a43a8c"));
+        assert!(blocks.iter().any(|b| b["type"] == "embed"));
+    }
+
+    #[actix_rt::test]
+    async fn overlapping_spans_work() {
+        let mut post = crate::proxy::tests::post();
+        let mixtape = post
+            .content
+            .body_model
+            .paragraphs
+            .iter_mut()
+            .find(|p| p.type_ == "MIXTAPE_EMBED")
+            .unwrap();
+        mixtape.mixtape_metadata = None;
+        let doc = document("7158b1cdd50c", &post, &[]);
+        let spans = doc
+            .blocks
+            .iter()
+            .find_map(|b| match b {
+                Block::Paragraph { spans } if spans[0].text.starts_with("Big Data") => Some(spans),
+                _ => None,
+            })
+            .unwrap();
+        // a link that spans bold and italic text
+        assert_eq!(spans[0].text, "Big Data, Small Effort");
+        assert_eq!(spans[0].marks, vec![Mark::Strong]);
+        assert_eq!(spans[2].marks, vec![Mark::Em]);
+        assert!(spans[..3].iter().all(|s| s.link.is_some()));
+    }
+
+    #[actix_rt::test]
+    async fn code_embeds_work() {
+        const FILE: &str = "https://github.com/realaravinth/libmedium/blob/8e1ab0c/src/data.rs";
+        let content = GistContent {
+            files: vec![GistFile {
+                file_name: "data.rs".into(),
+                content: "fn main() {}\n".into(),
+                language: String::default(),
+                raw_url: String::default(),
+            }],
+            html_url: FILE.into(),
+            cached: false,
+        };
+        let block = code_embed(FILE, &[(FILE.to_owned(), Some(content))]).unwrap();
+        let json = serde_json::to_value(block).unwrap();
+        assert_eq!(json["type"], "code_embed");
+        assert_eq!(json["host"], "GitHub");
+        assert_eq!(json["files"][0]["language"], "rust");
+
+        let block = code_embed(FILE, &[]).unwrap();
+        assert!(matches!(block, Block::CodeEmbed { files: None, .. }));
+        assert!(code_embed("https://www.youtube.com/watch?v=c1gO9aB9nbs", &[]).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn invalid_post_id_is_rejected() {
+        let app =
+            test::init_service(App::new().app_data(Data::temporary()).configure(services)).await;
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&V1_API_ROUTES.api.get_post("not-a-post"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
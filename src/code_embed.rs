@@ -0,0 +1,383 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Code embeds(IFRAME paragraphs that point to code)
+//!
+//! Code is fetched by the instance and rendered like gists, instead of being
+//! embedded as third-party iframes. Gists are fetched using GitHub's API, other
+//! hosts implement [CodeProvider] and are listed in [PROVIDERS].
+use std::fmt::Debug;
+
+use url::Url;
+
+use crate::data::GistRef;
+
+/// Host of single-file code embeds whose raw contents can be downloaded
+pub trait CodeProvider: Debug + Send + Sync {
+    /// Name of the host, as shown to readers
+    fn host(&self) -> &'static str;
+
+    /// URL of the raw contents of the embed
+    fn raw_url(&self) -> String;
+
+    /// Name of the embedded file, used when the host doesn't provide one
+    fn file_name(&self) -> String;
+
+    /// Whether the contents of the embed can change
+    fn is_mutable(&self) -> bool {
+        false
+    }
+
+    /// Lines that the embed is limited to
+    fn lines(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+/// Recognises embeds of a host
+type Parser = fn(&Url) -> Option<Box<dyn CodeProvider>>;
+
+/// Parsers of the supported hosts, tried in order
+const PROVIDERS: [Parser; 3] = [
+    |url| GitHubFile::parse(url).map(|p| Box::new(p) as Box<dyn CodeProvider>),
+    |url| GitLabSnippet::parse(url).map(|p| Box::new(p) as Box<dyn CodeProvider>),
+    |url| Pastebin::parse(url).map(|p| Box::new(p) as Box<dyn CodeProvider>),
+];
+
+#[derive(Debug)]
+pub enum CodeEmbed {
+    Gist(GistRef),
+    File(Box<dyn CodeProvider>),
+}
+
+impl CodeEmbed {
+    pub fn classify(href: &str) -> Option<Self> {
+        let url = Url::parse(href).ok()?;
+        if url.host_str()? == "gist.github.com" {
+            return GistRef::parse(href).map(Self::Gist);
+        }
+        PROVIDERS
+            .iter()
+            .find_map(|parse| parse(&url))
+            .map(Self::File)
+    }
+
+    /// Name of the host, as shown to readers
+    pub fn host(&self) -> &'static str {
+        match self {
+            Self::Gist(_) => "GitHub",
+            Self::File(file) => file.host(),
+        }
+    }
+
+    /// URL of the raw contents of the embed. Gists are fetched using GitHub's API.
+    pub fn raw_url(&self) -> Option<String> {
+        match self {
+            Self::Gist(_) => None,
+            Self::File(file) => Some(file.raw_url()),
+        }
+    }
+
+    /// Name of the embedded file, used when the host doesn't provide one
+    pub fn file_name(&self) -> String {
+        match self {
+            Self::Gist(gist) => gist.file.clone().unwrap_or_else(|| "gist".into()),
+            Self::File(file) => file.file_name(),
+        }
+    }
+
+    /// Whether the contents of the embed can change, see [CodeProvider::is_mutable]
+    pub fn is_mutable(&self) -> bool {
+        match self {
+            Self::Gist(_) => false,
+            Self::File(file) => file.is_mutable(),
+        }
+    }
+
+    /// Lines that the embed is limited to
+    pub fn lines(&self) -> Option<(usize, usize)> {
+        match self {
+            Self::Gist(_) => None,
+            Self::File(file) => file.lines(),
+        }
+    }
+}
+
+fn segments(url: &Url) -> Vec<&str> {
+    url.path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// `github.com/{owner}/{repo}/blob/{ref}/{path}#L{start}-L{end}`
+///
+/// Refs can contain slashes(`feature/x`), so where the ref ends and the path
+/// starts can't be told from the URL alone. Both are kept together, raw URLs
+/// accept them the same way blob URLs do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitHubFile {
+    owner: String,
+    repo: String,
+    /// `{ref}/{path}`
+    ref_path: String,
+    lines: Option<(usize, usize)>,
+}
+
+impl GitHubFile {
+    fn parse(url: &Url) -> Option<Self> {
+        if url.host_str()? != "github.com" {
+            return None;
+        }
+        match segments(url).as_slice() {
+            [owner, repo, "blob", ref_path @ ..] if ref_path.len() >= 2 => Some(Self {
+                owner: (*owner).to_owned(),
+                repo: (*repo).to_owned(),
+                ref_path: ref_path.join("/"),
+                lines: url.fragment().and_then(parse_lines),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl CodeProvider for GitHubFile {
+    fn host(&self) -> &'static str {
+        "GitHub"
+    }
+
+    fn raw_url(&self) -> String {
+        format!(
+            "https://raw.githubusercontent.com/{}/{}/{}",
+            self.owner, self.repo, self.ref_path
+        )
+    }
+
+    fn file_name(&self) -> String {
+        self.ref_path
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    }
+
+    /// Files are immutable only when they are pinned to a commit, branches and
+    /// tags can move. Commit hashes never contain slashes.
+    fn is_mutable(&self) -> bool {
+        let git_ref = self.ref_path.split('/').next().unwrap_or_default();
+        git_ref.len() != 40 || !git_ref.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    fn lines(&self) -> Option<(usize, usize)> {
+        self.lines
+    }
+}
+
+/// `gitlab.com/-/snippets/{id}` and `gitlab.com/{project}/-/snippets/{id}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitLabSnippet {
+    path: String,
+}
+
+impl GitLabSnippet {
+    fn parse(url: &Url) -> Option<Self> {
+        if url.host_str()? != "gitlab.com" {
+            return None;
+        }
+        let segments = segments(url);
+        let snippets = segments.iter().position(|s| *s == "snippets")?;
+        let id = segments.get(snippets + 1)?.trim_end_matches(".js");
+        if !id.chars().all(|c| c.is_ascii_digit()) || id.is_empty() {
+            return None;
+        }
+        let path = segments[..snippets]
+            .iter()
+            .chain(["snippets", id].iter())
+            .copied()
+            .collect::<Vec<&str>>()
+            .join("/");
+        Some(Self { path })
+    }
+}
+
+impl CodeProvider for GitLabSnippet {
+    fn host(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn raw_url(&self) -> String {
+        format!("https://gitlab.com/{}/raw", self.path)
+    }
+
+    fn file_name(&self) -> String {
+        self.path.rsplit('/').next().unwrap_or_default().to_owned()
+    }
+}
+
+/// `pastebin.com/{id}`, IDs are 8 alphanumeric characters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pastebin {
+    id: String,
+}
+
+impl Pastebin {
+    fn parse(url: &Url) -> Option<Self> {
+        if url.host_str()? != "pastebin.com" {
+            return None;
+        }
+        let id = match segments(url).as_slice() {
+            [id] | ["raw", id] | ["embed_iframe", id] | ["embed_js", id] => (*id).to_owned(),
+            _ => return None,
+        };
+        if id.len() != 8 || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        Some(Self { id })
+    }
+}
+
+impl CodeProvider for Pastebin {
+    fn host(&self) -> &'static str {
+        "Pastebin"
+    }
+
+    fn raw_url(&self) -> String {
+        format!("https://pastebin.com/raw/{}", self.id)
+    }
+
+    fn file_name(&self) -> String {
+        self.id.clone()
+    }
+}
+
+/// Parse GitHub's line fragments: `L10` and `L10-L20`
+fn parse_lines(fragment: &str) -> Option<(usize, usize)> {
+    let mut bounds = fragment.splitn(2, '-');
+    let start: usize = bounds.next()?.strip_prefix('L')?.parse().ok()?;
+    let end: usize = match bounds.next() {
+        Some(end) => end.strip_prefix('L')?.parse().ok()?,
+        None => start,
+    };
+    if start == 0 || end < start {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Keep lines `start..=end`(1-indexed) of `code`
+pub fn slice_lines(code: &str, (start, end): (usize, usize)) -> String {
+    code.lines()
+        .skip(start - 1)
+        .take(end - start + 1)
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_works() {
+        let file = CodeEmbed::classify(
+            "https://github.com/realaravinth/libmedium/blob/8e1ab0c/src/data.rs#L10-L20",
+        )
+        .unwrap();
+        assert_eq!(
+            file.raw_url().unwrap(),
+            "https://raw.githubusercontent.com/realaravinth/libmedium/8e1ab0c/src/data.rs"
+        );
+        assert_eq!(file.file_name(), "data.rs");
+        assert_eq!(file.lines(), Some((10, 20)));
+        assert_eq!(file.host(), "GitHub");
+
+        let file = CodeEmbed::classify(
+            "https://github.com/realaravinth/libmedium/blob/master/README.md#L3",
+        )
+        .unwrap();
+        assert_eq!(file.lines(), Some((3, 3)));
+        assert!(file.is_mutable());
+        assert!(CodeEmbed::classify("https://github.com/realaravinth/libmedium").is_none());
+        let file = CodeEmbed::classify(
+            "https://github.com/realaravinth/libmedium/blob/8e1ab0c2d2e9f6a7b8c9d0e1f2a3b4c5d6e7f8a9/README.md",
+        )
+        .unwrap();
+        assert!(!file.is_mutable());
+
+        let snippet = CodeEmbed::classify("https://gitlab.com/-/snippets/2018466").unwrap();
+        assert_eq!(
+            snippet.raw_url().unwrap(),
+            "https://gitlab.com/-/snippets/2018466/raw"
+        );
+        let snippet =
+            CodeEmbed::classify("https://gitlab.com/gitlab-org/gitlab/-/snippets/20.js").unwrap();
+        assert_eq!(
+            snippet.raw_url().unwrap(),
+            "https://gitlab.com/gitlab-org/gitlab/-/snippets/20/raw"
+        );
+
+        for url in [
+            "https://pastebin.com/UIFdu235",
+            "https://pastebin.com/embed_iframe/UIFdu235",
+            "https://pastebin.com/raw/UIFdu235",
+        ] {
+            let paste = CodeEmbed::classify(url).unwrap();
+            assert_eq!(
+                paste.raw_url().unwrap(),
+                "https://pastebin.com/raw/UIFdu235"
+            );
+        }
+
+        for url in [
+            "https://pastebin.com/archive",
+            "https://pastebin.com/UIFdu23",
+            "https://pastebin.com/UIFdu2356",
+            "https://pastebin.com/raw/UIFdu23-",
+        ] {
+            assert!(CodeEmbed::classify(url).is_none(), "{url}");
+        }
+
+        let file = CodeEmbed::classify(
+            "https://github.com/realaravinth/libmedium/blob/feature/code-embeds/src/data.rs#L5",
+        )
+        .unwrap();
+        assert_eq!(
+            file.raw_url().unwrap(),
+            "https://raw.githubusercontent.com/realaravinth/libmedium/feature/code-embeds/src/data.rs"
+        );
+        assert_eq!(file.file_name(), "data.rs");
+        assert_eq!(file.lines(), Some((5, 5)));
+        assert!(file.is_mutable());
+        assert!(
+            CodeEmbed::classify("https://github.com/realaravinth/libmedium/blob/master").is_none()
+        );
+
+        let gist = CodeEmbed::classify("https://gist.github.com/realaravinth/2059237").unwrap();
+        assert!(matches!(gist, CodeEmbed::Gist(_)));
+        assert!(gist.raw_url().is_none());
+
+        assert!(CodeEmbed::classify("https://www.youtube.com/watch?v=c1gO9aB9nbs").is_none());
+    }
+
+    #[test]
+    fn slice_lines_works() {
+        let code = "a\nb\nc\nd\n";
+        assert_eq!(slice_lines(code, (2, 3)), "b\nc");
+        assert_eq!(slice_lines(code, (4, 10)), "d");
+        assert_eq!(parse_lines("L0"), None);
+        assert_eq!(parse_lines("L5-L2"), None);
+        assert_eq!(parse_lines("diff-123"), None);
+    }
+}
@@ -15,28 +15,124 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_web::web;
 use graphql_client::{reqwest::post_graphql, GraphQLQuery};
-use reqwest::header::USER_AGENT;
+use reqwest::header::{LOCATION, USER_AGENT};
+use reqwest::redirect::Policy;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use sha256::digest;
 use sled::{Db, Tree};
 
+use crate::code_embed::CodeEmbed;
 use crate::proxy::StringUtils;
 use crate::render_html;
 use crate::SETTINGS;
 
-const POST_CACHE_VERSION: usize = 3;
-const GIST_CACHE_VERSION: usize = 1;
+const POST_CACHE_VERSION: usize = 9;
+const GIST_CACHE_VERSION: usize = 2;
+const RESPONSES_CACHE_VERSION: usize = 6;
+const USER_CACHE_VERSION: usize = 1;
+const USER_IDS_CACHE_VERSION: usize = 1;
+const EXTERNAL_ASSETS_CACHE_VERSION: usize = 1;
+const MEDIA_CACHE_VERSION: usize = 1;
+const CODE_EMBEDS_CACHE_VERSION: usize = 2;
+/// Bump when the markup of highlighted code or rendered gists changes
+const HIGHLIGHTS_CACHE_VERSION: usize = 3;
+
+/// Redirects followed when resolving post URLs
+const MAX_REDIRECTS: usize = 10;
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A slow gist shouldn't hold up the whole post
+const GIST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Author pages list posts, so they are refreshed once a day. Usernames of user IDs
+/// are refreshed as often, users can change them.
+const USER_CACHE_TTL: i64 = 60 * 60 * 24;
+/// New responses are picked up once a day
+const RESPONSES_CACHE_TTL: i64 = 60 * 60 * 24;
+/// Code embeds that can change, see [CodeEmbed::is_mutable], are refreshed once a day
+const CODE_EMBEDS_CACHE_TTL: i64 = 60 * 60 * 24;
 
 #[derive(Clone)]
 pub struct Data {
     pub client: Client,
+    /// Only follows redirects to Medium, see [Data::resolve_post_id]
+    resolver: Client,
+    #[allow(dead_code)]
     cache: Db,
     pub posts: Tree,
     pub gists: Tree,
+    pub responses: Tree,
+    pub users: Tree,
+    /// Usernames of user IDs, see [Data::get_user_light]
+    pub user_ids: Tree,
+    /// Third-party assets(embed thumbnails) that may be served by the instance,
+    /// keyed by the digest of their URL
+    pub external_assets: Tree,
+    /// Sanitized media pages, see [crate::media]
+    pub media: Tree,
+    /// Code embeds other than gists, keyed by their raw URL. See [crate::code_embed]
+    pub code_embeds: Tree,
+    /// Highlighted code and rendered gist files, see [Data::highlight]
+    pub highlights: Tree,
+    pub github_rate_limit: Arc<crate::github::RateLimit>,
+}
+
+/// Gist embedded in a post: `https://gist.github.com/{user}/{id}[/{revision}][.js][?file={file}]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GistRef {
+    pub user: Option<String>,
+    pub id: String,
+    /// Gists embedded with a revision are pinned to it
+    pub revision: Option<String>,
+    /// Name of the file that the embed is limited to
+    pub file: Option<String>,
+}
+
+impl GistRef {
+    pub fn parse(url: &str) -> Option<Self> {
+        let url = url::Url::parse(url).ok()?;
+        let mut segments: Vec<&str> = url.path_segments()?.filter(|s| !s.is_empty()).collect();
+        if let Some(last) = segments.last_mut() {
+            *last = last.trim_end_matches(".js");
+        }
+        let (user, id, revision) = match segments.as_slice() {
+            [id] => (None, *id, None),
+            [user, id] => (Some(*user), *id, None),
+            [user, id, revision, ..] => (Some(*user), *id, Some(*revision)),
+            [] => return None,
+        };
+
+        let is_hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+        if !is_hex(id) || !revision.is_none_or(is_hex) {
+            return None;
+        }
+
+        let file = url
+            .query_pairs()
+            .find(|(k, _)| k == "file")
+            .map(|(_, file)| file.into_owned());
+
+        Some(Self {
+            user: user.map(|u| u.to_owned()),
+            id: id.to_owned(),
+            revision: revision.map(|r| r.to_owned()),
+            file,
+        })
+    }
+
+    /// Key of the gist in the cache, revisions are cached separately
+    pub fn key(&self) -> String {
+        match &self.revision {
+            Some(revision) => format!("{}/{revision}", self.id),
+            None => self.id.clone(),
+        }
+    }
 }
 
 #[derive(GraphQLQuery)]
@@ -61,6 +157,10 @@ impl PostResp {
 pub struct GistContent {
     pub files: Vec<GistFile>,
     pub html_url: String,
+    /// Whether files are served by the instance. Gists fetched without the API
+    /// might be missing files, so they aren't cached or served.
+    #[serde(skip)]
+    pub cached: bool,
 }
 
 #[derive(Deserialize, Clone, Serialize)]
@@ -93,6 +193,262 @@ impl GistFile {
 )]
 pub struct GetPostLight;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema.graphql",
+    query_path = "schemas/query.graphql",
+    response_derives = "Debug, Serialize, Deserialize, Clone"
+)]
+pub struct GetPostResponses;
+
+pub type Paragraph = get_post::GetPostPostContentBodyModelParagraphs;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema.graphql",
+    query_path = "schemas/query.graphql",
+    response_derives = "Debug, Serialize, Deserialize, Clone"
+)]
+pub struct GetUser;
+
+pub type UserResp = get_user::GetUserUser;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schemas/schema.graphql",
+    query_path = "schemas/query.graphql",
+    response_derives = "Debug, Serialize, Deserialize, Clone"
+)]
+pub struct GetUserLight;
+
+#[derive(Deserialize, Serialize)]
+struct CachedUser {
+    fetched_at: i64,
+    user: UserResp,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedUsername {
+    fetched_at: i64,
+    username: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedResponses {
+    fetched_at: i64,
+    responses: Vec<PostResponse>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedCodeEmbed {
+    fetched_at: i64,
+    file: GistFile,
+}
+
+/// Why something couldn't be fetched from Medium
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchError {
+    /// Medium doesn't know about it, or it was deleted
+    NotFound,
+    /// Medium couldn't be reached or sent an unexpected response
+    Upstream,
+}
+
+/// A response(comment) to a post, along with the replies it received
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PostResponse {
+    pub id: String,
+    pub created_at: i64,
+    pub creator: get_post::GetPostPostCreator,
+    pub paragraphs: Vec<Paragraph>,
+    pub replies: Vec<PostResponse>,
+}
+
+impl PostResponse {
+    /// Responses to a post, along with their replies
+    pub fn threads(post: get_post_responses::GetPostResponsesPost) -> Vec<Self> {
+        let threads = match post.threaded_post_responses {
+            Some(threads) => threads.posts,
+            None => return Vec::default(),
+        };
+        threads
+            .into_iter()
+            .map(|r| {
+                let mut response = Self::new(
+                    r.id,
+                    r.created_at,
+                    r.creator,
+                    r.content.body_model.paragraphs,
+                );
+                if let Some(replies) = r.threaded_post_responses {
+                    response.replies = replies
+                        .posts
+                        .into_iter()
+                        .map(|reply| {
+                            Self::new(
+                                reply.id,
+                                reply.created_at,
+                                reply.creator,
+                                reply.content.body_model.paragraphs,
+                            )
+                        })
+                        .collect();
+                }
+                response
+            })
+            .collect()
+    }
+
+    fn new(
+        id: String,
+        created_at: i64,
+        creator: get_post_responses::ResponseCreator,
+        paragraphs: Vec<get_post_responses::ResponseParagraph>,
+    ) -> Self {
+        Self {
+            id,
+            created_at,
+            creator: creator.into(),
+            paragraphs: paragraphs.into_iter().map(Paragraph::from).collect(),
+            replies: Vec::default(),
+        }
+    }
+}
+
+// Responses are posts too, so their paragraphs are converted to [GetPost]'s to reuse
+// the post renderer. Fields are listed without `..`, so that the conversion doesn't
+// compile when the selections of the two queries drift apart.
+
+impl From<get_post_responses::ResponseCreator> for get_post::GetPostPostCreator {
+    fn from(creator: get_post_responses::ResponseCreator) -> Self {
+        let get_post_responses::ResponseCreator {
+            name,
+            id,
+            image_id,
+            username,
+        } = creator;
+        Self {
+            name,
+            id,
+            image_id,
+            username,
+        }
+    }
+}
+
+impl From<get_post_responses::ResponseParagraph> for Paragraph {
+    fn from(p: get_post_responses::ResponseParagraph) -> Self {
+        use get_post::*;
+        use get_post_responses::*;
+
+        let ResponseParagraph {
+            text,
+            type_,
+            href,
+            layout,
+            markups,
+            iframe,
+            metadata,
+            code_block_metadata,
+            mixtape_metadata,
+        } = p;
+        let markups = markups
+            .into_iter()
+            .map(|m| {
+                let ResponseParagraphMarkups {
+                    title,
+                    type_,
+                    href,
+                    user_id,
+                    start,
+                    end,
+                    anchor_type,
+                } = m;
+                GetPostPostContentBodyModelParagraphsMarkups {
+                    title,
+                    type_,
+                    href,
+                    user_id,
+                    start,
+                    end,
+                    anchor_type,
+                }
+            })
+            .collect();
+        let iframe = iframe.map(|iframe| {
+            let ResponseParagraphIframe { media_resource } = iframe;
+            GetPostPostContentBodyModelParagraphsIframe {
+                media_resource: media_resource.map(|r| {
+                    let ResponseParagraphIframeMediaResource {
+                        id,
+                        href,
+                        iframe_src,
+                        iframe_width,
+                        iframe_height,
+                        title,
+                        thumbnail_url,
+                    } = r;
+                    GetPostPostContentBodyModelParagraphsIframeMediaResource {
+                        id,
+                        href,
+                        iframe_src,
+                        iframe_width,
+                        iframe_height,
+                        title,
+                        thumbnail_url,
+                    }
+                }),
+            }
+        });
+        let metadata = metadata.map(|m| {
+            let ResponseParagraphMetadata {
+                id,
+                original_width,
+                original_height,
+            } = m;
+            GetPostPostContentBodyModelParagraphsMetadata {
+                id,
+                original_width,
+                original_height,
+            }
+        });
+        let code_block_metadata = code_block_metadata.map(|m| {
+            let ResponseParagraphCodeBlockMetadata { lang } = m;
+            GetPostPostContentBodyModelParagraphsCodeBlockMetadata { lang }
+        });
+        let mixtape_metadata = mixtape_metadata.map(|m| {
+            let ResponseParagraphMixtapeMetadata {
+                href,
+                thumbnail_image_id,
+                media_resource,
+            } = m;
+            GetPostPostContentBodyModelParagraphsMixtapeMetadata {
+                href,
+                thumbnail_image_id,
+                media_resource: media_resource.map(|r| {
+                    let ResponseParagraphMixtapeMetadataMediaResource { title, description } = r;
+                    GetPostPostContentBodyModelParagraphsMixtapeMetadataMediaResource {
+                        title,
+                        description,
+                    }
+                }),
+            }
+        });
+
+        Self {
+            text,
+            type_,
+            href,
+            layout,
+            markups,
+            iframe,
+            metadata,
+            code_block_metadata,
+            mixtape_metadata,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PostUrl {
     pub slug: String,
@@ -102,211 +458,797 @@ pub struct PostUrl {
 impl Data {
     pub fn new() -> AppData {
         let path = Path::new(SETTINGS.cache.as_ref().unwrap()).join("posts_cache");
-        let cache = sled::open(path).unwrap();
+        Self::open(sled::open(path).unwrap())
+    }
+
+    /// Data with a cache that is deleted when it's dropped, for tests that write to it
+    #[cfg(test)]
+    pub fn temporary() -> AppData {
+        Self::open(sled::Config::new().temporary(true).open().unwrap())
+    }
+
+    fn open(cache: Db) -> AppData {
         let posts = cache.open_tree("posts").unwrap();
         let gists = cache.open_tree("gists").unwrap();
+        let responses = cache.open_tree("responses").unwrap();
+        let users = cache.open_tree("users").unwrap();
+        let user_ids = cache.open_tree("user_ids").unwrap();
+        let external_assets = cache.open_tree("external_assets").unwrap();
+        let media = cache.open_tree("media").unwrap();
+        let code_embeds = cache.open_tree("code_embeds").unwrap();
+        let highlights = cache.open_tree("highlights").unwrap();
         let res = Self {
             client: Client::new(),
+            resolver: Self::resolver(),
             cache,
             posts,
             gists,
+            responses,
+            users,
+            user_ids,
+            external_assets,
+            media,
+            code_embeds,
+            highlights,
+            github_rate_limit: Arc::default(),
         };
         res.migrate();
 
         AppData::new(res)
     }
 
+    fn resolver() -> Client {
+        let policy = Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if crate::resolve::is_resolvable(attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        });
+        Client::builder().redirect(policy).build().unwrap()
+    }
+
+    /// Clear trees whose version differs from the current one. Trees without a
+    /// version(created by builds that predate versioning of the tree) are cleared
+    /// too, so upgrading drops entries that might not deserialize. Everything in
+    /// the cache is fetched again on demand.
     fn migrate(&self) {
         const POST_KEY: &str = "POST_CACHE_VERSION";
         const GIST_KEY: &str = "GIST_CACHE_VERSION";
+        const RESPONSES_KEY: &str = "RESPONSES_CACHE_VERSION";
+        const USER_KEY: &str = "USER_CACHE_VERSION";
+        const USER_IDS_KEY: &str = "USER_IDS_CACHE_VERSION";
+        const EXTERNAL_ASSETS_KEY: &str = "EXTERNAL_ASSETS_CACHE_VERSION";
+        const MEDIA_KEY: &str = "MEDIA_CACHE_VERSION";
+        const CODE_EMBEDS_KEY: &str = "CODE_EMBEDS_CACHE_VERSION";
+        const HIGHLIGHTS_KEY: &str = "HIGHLIGHTS_CACHE_VERSION";
         let trees = [
             (&self.posts, POST_KEY, POST_CACHE_VERSION),
             (&self.gists, GIST_KEY, GIST_CACHE_VERSION),
+            (&self.responses, RESPONSES_KEY, RESPONSES_CACHE_VERSION),
+            (&self.users, USER_KEY, USER_CACHE_VERSION),
+            (&self.user_ids, USER_IDS_KEY, USER_IDS_CACHE_VERSION),
+            (
+                &self.external_assets,
+                EXTERNAL_ASSETS_KEY,
+                EXTERNAL_ASSETS_CACHE_VERSION,
+            ),
+            (&self.media, MEDIA_KEY, MEDIA_CACHE_VERSION),
+            (
+                &self.code_embeds,
+                CODE_EMBEDS_KEY,
+                CODE_EMBEDS_CACHE_VERSION,
+            ),
+            (&self.highlights, HIGHLIGHTS_KEY, HIGHLIGHTS_CACHE_VERSION),
         ];
 
+        let mut upgraded = Vec::with_capacity(trees.len());
         for (tree, key, current_version) in trees {
-            if let Ok(Some(v)) = tree.get(key) {
-                let version = bincode::deserialize::<usize>(&v[..]).unwrap();
-                let clean = version != current_version;
-
-                if clean {
-                    log::info!(
-                        "Upgrading {} from version {} to version {}",
-                        key,
-                        version,
-                        current_version
-                    );
-                    tree.clear().unwrap();
-                    tree.flush().unwrap();
-                    tree.insert(key, bincode::serialize(&current_version).unwrap())
-                        .unwrap();
-                }
+            let version = match tree.get(key) {
+                Ok(Some(v)) => Some(bincode::deserialize::<usize>(&v[..]).unwrap()),
+                _ => None,
+            };
+
+            if version != Some(current_version) {
+                log::info!(
+                    "Upgrading {} from version {:?} to version {}",
+                    key,
+                    version,
+                    current_version
+                );
+                tree.clear().unwrap();
+                tree.flush().unwrap();
+                tree.insert(key, bincode::serialize(&current_version).unwrap())
+                    .unwrap();
+                upgraded.push(key);
             }
         }
-    }
 
-    pub async fn get_post(&self, id: &str) -> PostResp {
-        match self.posts.get(id) {
-            Ok(Some(v)) => bincode::deserialize(&v[..]).unwrap(),
+        // highlighted code depends on the configured syntaxes, which aren't part of
+        // memoisation keys. Rendered gists link to registered external assets, which
+        // are gone once that tree is cleared.
+        const SYNTAXES_KEY: &str = "HIGHLIGHTS_SYNTAXES";
+        let fingerprint = render_html::syntax_fingerprint(
+            SETTINGS.syntaxes_dir.as_deref(),
+            &SETTINGS.syntax_overrides,
+        );
+        let assets_cleared = upgraded.contains(&EXTERNAL_ASSETS_KEY);
+        match self.highlights.get(SYNTAXES_KEY) {
+            Ok(Some(v)) if v == fingerprint.as_bytes() && !assets_cleared => (),
             _ => {
-                let vars = get_post::Variables { id: id.to_owned() };
-                const URL: &str = "https://medium.com/_/graphql";
-
-                let res = post_graphql::<GetPost, _>(&self.client, URL, vars)
-                    .await
+                log::info!("Syntaxes or external assets changed, clearing highlighted code");
+                self.highlights.clear().unwrap();
+                self.highlights
+                    .insert(
+                        HIGHLIGHTS_KEY,
+                        bincode::serialize(&HIGHLIGHTS_CACHE_VERSION).unwrap(),
+                    )
                     .unwrap();
-                let res = res.data.expect("missing response data").post.unwrap();
-                self.posts
-                    .insert(id, bincode::serialize(&res).unwrap())
+                self.highlights
+                    .insert(SYNTAXES_KEY, fingerprint.as_str())
                     .unwrap();
-                res
+                self.highlights.flush().unwrap();
             }
         }
     }
 
-    pub async fn get_post_light(&self, id: &str) -> PostUrl {
-        match self.posts.get(id) {
-            Ok(Some(v)) => {
-                let cached: PostResp = bincode::deserialize(&v[..]).unwrap();
-                PostUrl {
-                    slug: cached.unique_slug,
-                    username: cached.creator.username,
-                }
+    pub async fn get_post(&self, id: &str) -> Result<PostResp, FetchError> {
+        if let Ok(Some(v)) = self.posts.get(id) {
+            return Ok(bincode::deserialize(&v[..]).unwrap());
+        }
+
+        let vars = get_post::Variables { id: id.to_owned() };
+        const URL: &str = "https://medium.com/_/graphql";
+
+        let res = match post_graphql::<GetPost, _>(&self.client, URL, vars).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("couldn't fetch post {id}: {e}");
+                return Err(FetchError::Upstream);
             }
-            _ => {
-                let vars = get_post_light::Variables { id: id.to_owned() };
-                const URL: &str = "https://medium.com/_/graphql";
+        };
+        let res = res
+            .data
+            .ok_or(FetchError::Upstream)?
+            .post
+            .ok_or(FetchError::NotFound)?;
+        self.posts
+            .insert(id, bincode::serialize(&res).unwrap())
+            .unwrap();
+        Ok(res)
+    }
 
-                let res = post_graphql::<GetPostLight, _>(&self.client, URL, vars)
-                    .await
-                    .unwrap();
-                let res = res.data.expect("missing response data").post.unwrap();
-                PostUrl {
-                    slug: res.unique_slug,
-                    username: res.creator.username,
-                }
+    pub async fn get_post_light(&self, id: &str) -> Result<PostUrl, FetchError> {
+        if let Ok(Some(v)) = self.posts.get(id) {
+            let cached: PostResp = bincode::deserialize(&v[..]).unwrap();
+            return Ok(PostUrl {
+                slug: cached.unique_slug,
+                username: cached.creator.username,
+            });
+        }
+
+        let vars = get_post_light::Variables { id: id.to_owned() };
+        const URL: &str = "https://medium.com/_/graphql";
+
+        let res = match post_graphql::<GetPostLight, _>(&self.client, URL, vars).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("couldn't fetch post {id}: {e}");
+                return Err(FetchError::Upstream);
+            }
+        };
+        let res = res
+            .data
+            .ok_or(FetchError::Upstream)?
+            .post
+            .ok_or(FetchError::NotFound)?;
+        Ok(PostUrl {
+            slug: res.unique_slug,
+            username: res.creator.username,
+        })
+    }
+
+    pub async fn get_responses(&self, id: &str) -> Result<Vec<PostResponse>, FetchError> {
+        let now = chrono::Utc::now().timestamp();
+        if let Ok(Some(v)) = self.responses.get(id) {
+            let cached: CachedResponses = bincode::deserialize(&v[..]).unwrap();
+            if now - cached.fetched_at < RESPONSES_CACHE_TTL {
+                return Ok(cached.responses);
+            }
+        }
+
+        let vars = get_post_responses::Variables { id: id.to_owned() };
+        const URL: &str = "https://medium.com/_/graphql";
+
+        let res = match post_graphql::<GetPostResponses, _>(&self.client, URL, vars).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("couldn't fetch responses of {id}: {e}");
+                return Err(FetchError::Upstream);
+            }
+        };
+        let post = res
+            .data
+            .ok_or(FetchError::Upstream)?
+            .post
+            .ok_or(FetchError::NotFound)?;
+        let cached = CachedResponses {
+            fetched_at: now,
+            responses: PostResponse::threads(post),
+        };
+        self.responses
+            .insert(id, bincode::serialize(&cached).unwrap())
+            .unwrap();
+        Ok(cached.responses)
+    }
+
+    /// Get a user by username. `None` when the user doesn't exist or Medium couldn't be
+    /// reached.
+    pub async fn get_user(&self, username: &str) -> Option<UserResp> {
+        let now = chrono::Utc::now().timestamp();
+        if let Ok(Some(v)) = self.users.get(username) {
+            let cached: CachedUser = bincode::deserialize(&v[..]).unwrap();
+            if now - cached.fetched_at < USER_CACHE_TTL {
+                return Some(cached.user);
             }
         }
+
+        let vars = get_user::Variables {
+            username: username.to_owned(),
+        };
+        const URL: &str = "https://medium.com/_/graphql";
+
+        let res = match post_graphql::<GetUser, _>(&self.client, URL, vars).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("couldn't fetch user {username}: {e}");
+                return None;
+            }
+        };
+        let user = res.data?.user?;
+        let cached = CachedUser {
+            fetched_at: now,
+            user,
+        };
+        self.users
+            .insert(username, bincode::serialize(&cached).unwrap())
+            .unwrap();
+        Some(cached.user)
     }
 
-    pub fn get_gist_id(url: &str) -> &str {
-        let mut id = url.split('/').last().unwrap();
-        id = if id.ends_with(".js") {
-            let splits: Vec<&str> = id.split(".js").collect();
-            splits.get(0).unwrap()
-        } else {
-            id
+    /// Get the username of a user ID. `None` when the user doesn't exist or Medium
+    /// couldn't be reached.
+    pub async fn get_user_light(&self, id: &str) -> Option<String> {
+        let now = chrono::Utc::now().timestamp();
+        if let Ok(Some(v)) = self.user_ids.get(id) {
+            let cached: CachedUsername = bincode::deserialize(&v[..]).unwrap();
+            if now - cached.fetched_at < USER_CACHE_TTL {
+                return Some(cached.username);
+            }
+        }
+
+        let vars = get_user_light::Variables { id: id.to_owned() };
+        const URL: &str = "https://medium.com/_/graphql";
+
+        let res = match post_graphql::<GetUserLight, _>(&self.client, URL, vars).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("couldn't fetch user {id}: {e}");
+                return None;
+            }
         };
-        id
+        let cached = CachedUsername {
+            fetched_at: now,
+            username: res.data?.user?.username,
+        };
+        self.user_ids
+            .insert(id, bincode::serialize(&cached).unwrap())
+            .unwrap();
+        Some(cached.username)
     }
 
-    pub async fn get_gist(&self, gist_url: String) -> (String, GistContent) {
-        let mut id = Self::get_gist_id(&gist_url).to_owned();
-        let file_name = if gist_url.contains('?') {
-            let parsed = url::Url::parse(&gist_url).unwrap();
-            if let Some((_, file_name)) = parsed.query_pairs().find(|(k, _)| k == "file") {
-                Some(file_name.into_owned())
-            } else {
+    /// Register a third-party asset and get the instance URL that serves it. Only
+    /// registered assets are proxied, so the asset route can't be used as an open proxy.
+    /// Assets are registered once, rendering a post again doesn't write to the cache.
+    pub fn get_external_asset(&self, url: &str) -> String {
+        let digest = digest(url);
+        if !self.external_assets.contains_key(&digest).unwrap_or(false) {
+            self.external_assets.insert(&digest, url).unwrap();
+        }
+        crate::V1_API_ROUTES.proxy.get_external_asset(&digest)
+    }
+
+    pub async fn get_media(&self, id: &str) -> Option<String> {
+        if let Ok(Some(v)) = self.media.get(id) {
+            return Some(bincode::deserialize(&v[..]).unwrap());
+        }
+
+        let url = format!("https://medium.com/media/{id}");
+        let res = self
+            .client
+            .get(url)
+            .header(USER_AGENT, "libmedium")
+            .send()
+            .await
+            .ok()?;
+        if !res.status().is_success() {
+            return None;
+        }
+        let html = crate::media::sanitize(&res.text().await.ok()?);
+        self.media
+            .insert(id, bincode::serialize(&html).unwrap())
+            .unwrap();
+        Some(html)
+    }
+
+    /// Resolve post ID of URLs that [crate::resolve::get_post_id] can't parse, like short
+    /// links and slugs without IDs, by following redirects and looking for the
+    /// `medium://p/{id}` app link that Medium embeds in post pages. Only Medium and
+    /// custom domains are requested, see [crate::resolve::is_resolvable].
+    pub async fn resolve_post_id(&self, url: &str) -> Option<String> {
+        let url = url::Url::parse(url).ok()?;
+        if !crate::resolve::is_resolvable(&url) {
+            return None;
+        }
+
+        let res = self
+            .resolver
+            .get(url)
+            .header(USER_AGENT, "libmedium")
+            .timeout(RESOLVE_TIMEOUT)
+            .send()
+            .await
+            .ok()?;
+        if let Some(id) = crate::resolve::get_post_id(res.url().as_str()) {
+            return Some(id);
+        }
+        if res.status().is_redirection() {
+            // redirects to other hosts aren't followed, but they can point to posts
+            let location = res.headers().get(LOCATION)?.to_str().ok()?;
+            return crate::resolve::get_post_id(location);
+        }
+
+        const APP_LINK: &str = "medium://p/";
+        let body = res.text().await.ok()?;
+        body.match_indices(APP_LINK)
+            .filter_map(|(start, _)| {
+                body[start + APP_LINK.len()..]
+                    .split(|c: char| !c.is_ascii_alphanumeric())
+                    .next()
+            })
+            .find(|id| crate::resolve::is_post_id(id))
+            .map(|id| id.to_owned())
+    }
+
+    /// Highlight code, memoised by `key`. `key` must change whenever the code or the
+    /// line IDs(derived from `id`) do: the digest of a gist's raw URL, which
+    /// includes the revision of the gist, is one such key. The memo is cleared at
+    /// startup when the configured syntaxes change.
+    pub fn highlight(&self, key: &str, id: &str, query: &render_html::SourcegraphQuery) -> String {
+        self.memoise(key, || query.syntax_highlight(id))
+    }
+
+    /// Render a gist file based on its type, see [crate::gist]
+    fn render_gist_file(&self, file: &GistFile) -> String {
+        let id = digest(&file.raw_url);
+        // raw URLs of files fetched without the API aren't pinned to a revision
+        let key = digest(format!("{}\n{}", file.raw_url, file.content));
+        self.memoise(&key, || crate::gist::render(file, &id, self))
+    }
+
+    fn memoise(&self, key: &str, render: impl FnOnce() -> String) -> String {
+        if let Ok(Some(v)) = self.highlights.get(key) {
+            return bincode::deserialize(&v[..]).unwrap();
+        }
+        let html = render();
+        self.highlights
+            .insert(key, bincode::serialize(&html).unwrap())
+            .unwrap();
+        html
+    }
+
+    /// Get a code embed(see [crate::code_embed]) with rendered files. Failures(hosts
+    /// being unreachable or rate-limiting the instance, deleted gists, etc.) are logged
+    /// and `None` is returned, so that posts can be rendered without the embed.
+    pub async fn get_code_embed(&self, url: String) -> (String, Option<GistContent>) {
+        let (url, content) = self.get_code_embed_source(url).await;
+        let content = content.map(|mut content| {
+            content
+                .files
+                .iter_mut()
+                .for_each(|f| f.content = self.render_gist_file(f));
+            content
+        });
+        (url, content)
+    }
+
+    /// Like [Data::get_code_embed], but files aren't rendered
+    pub async fn get_code_embed_source(&self, url: String) -> (String, Option<GistContent>) {
+        let content = match CodeEmbed::classify(&url) {
+            Some(CodeEmbed::Gist(gist)) => self.load_gist(&gist, &url).await,
+            Some(embed) => self.load_code_embed(&embed, &url).await,
+            None => {
+                log::warn!("{url} isn't a code embed");
                 None
             }
-        } else {
-            None
         };
+        (url, content)
+    }
 
-        let mut gist = match self.gists.get(&id) {
-            Ok(Some(v)) => bincode::deserialize(&v[..]).unwrap(),
-            _ => {
-                const URL: &str = "https://api.github.com/gists/";
-
-                let url = format!("{}{}", URL, id);
-                println!("{url}");
-
-                let resp = self
-                    .client
-                    .get(&url)
-                    .header(USER_AGENT, "libmedium")
-                    .send()
-                    .await
-                    .unwrap()
-                    .json::<serde_json::Value>()
-                    .await
-                    .unwrap();
-                let files = resp.get("files").unwrap();
-                let v = files.as_object().unwrap();
-
-                fn to_gist_file(name: &str, file_obj: &serde_json::Value) -> GistFile {
-                    GistFile {
-                        file_name: name.to_string(),
-                        content: file_obj
-                            .get("content")
-                            .unwrap()
-                            .as_str()
-                            .unwrap()
-                            .to_owned(),
-                        language: file_obj
-                            .get("language")
-                            .unwrap()
-                            .as_str()
-                            .unwrap()
-                            .to_owned(),
-                        raw_url: file_obj
-                            .get("raw_url")
-                            .unwrap()
-                            .as_str()
-                            .unwrap()
-                            .to_owned(),
+    async fn load_code_embed(&self, embed: &CodeEmbed, url: &str) -> Option<GistContent> {
+        let raw_url = embed.raw_url()?;
+        let now = chrono::Utc::now().timestamp();
+        let cached = match self.code_embeds.get(&raw_url) {
+            Ok(Some(v)) => Some(bincode::deserialize::<CachedCodeEmbed>(&v[..]).unwrap()),
+            _ => None,
+        };
+        let mut file = match cached {
+            Some(cached)
+                if !embed.is_mutable() || now - cached.fetched_at < CODE_EMBEDS_CACHE_TTL =>
+            {
+                cached.file
+            }
+            stale => match self.fetch_code_embed(embed, &raw_url).await {
+                Some(file) => {
+                    let cached = CachedCodeEmbed {
+                        fetched_at: now,
+                        file,
+                    };
+                    self.code_embeds
+                        .insert(&raw_url, bincode::serialize(&cached).unwrap())
+                        .unwrap();
+                    cached.file
+                }
+                // stale contents are better than none when the host can't be reached
+                None => stale?.file,
+            },
+        };
+
+        if let Some(lines) = embed.lines() {
+            file.content = crate::code_embed::slice_lines(&file.content, lines);
+            // line IDs are derived from the raw URL, embeds of different lines of
+            // a file can be on the same page
+            file.raw_url = format!("{raw_url}#L{}-L{}", lines.0, lines.1);
+        }
+
+        Some(GistContent {
+            files: vec![file],
+            html_url: url.to_owned(),
+            cached: false,
+        })
+    }
+
+    async fn fetch_code_embed(&self, embed: &CodeEmbed, raw_url: &str) -> Option<GistFile> {
+        let resp = self
+            .client
+            .get(raw_url)
+            .header(USER_AGENT, "libmedium")
+            .timeout(GIST_TIMEOUT)
+            .send()
+            .await;
+        let resp = match resp {
+            Ok(resp) if resp.status().is_success() => resp,
+            Ok(resp) => {
+                log::warn!("couldn't fetch {raw_url}: {}", resp.status());
+                return None;
+            }
+            Err(e) => {
+                log::warn!("couldn't fetch {raw_url}: {e}");
+                return None;
+            }
+        };
+        // GitLab sends names of snippet files
+        let file_name = resp
+            .headers()
+            .get(reqwest::header::CONTENT_DISPOSITION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split("filename=").nth(1))
+            .map(|name| name.split(';').next().unwrap().trim().trim_matches('"'))
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_owned())
+            .unwrap_or_else(|| embed.file_name());
+        let content = resp.text().await.ok()?;
+
+        Some(GistFile {
+            file_name,
+            content,
+            language: String::default(),
+            raw_url: raw_url.to_owned(),
+        })
+    }
+
+    async fn load_gist(&self, gist_ref: &GistRef, gist_url: &str) -> Option<GistContent> {
+        let key = gist_ref.key();
+        let gist = match self.get_cached_gist(&key) {
+            Some(gist) => gist,
+            None => match self.fetch_gist(gist_ref).await {
+                Some(gist) => {
+                    self.gists
+                        .insert(&key, bincode::serialize(&gist).unwrap())
+                        .unwrap();
+                    GistContent {
+                        cached: true,
+                        ..gist
                     }
                 }
+                // not cached, raw gists might be missing files
+                None => self.fetch_raw_gist(gist_ref, gist_url).await?,
+            },
+        };
 
-                let mut files = Vec::with_capacity(v.len());
-                v.iter().for_each(|(name, file_obj)| {
-                    let file = to_gist_file(name, file_obj);
-                    files.push(file);
-                });
+        match &gist_ref.file {
+            Some(file_name) => match gist.files.into_iter().find(|f| &f.file_name == file_name) {
+                Some(file) => Some(GistContent {
+                    files: vec![file],
+                    html_url: gist_url.to_owned(),
+                    cached: gist.cached,
+                }),
+                None => {
+                    log::warn!("gist {key} doesn't have file {file_name}");
+                    None
+                }
+            },
+            None => Some(gist),
+        }
+    }
 
-                let gist = GistContent {
-                    files,
-                    html_url: resp.get("html_url").unwrap().as_str().unwrap().to_owned(),
-                };
+    /// Get a gist from the cache, `key` is a [GistRef::key]. Files aren't rendered.
+    pub fn get_cached_gist(&self, key: &str) -> Option<GistContent> {
+        match self.gists.get(key) {
+            Ok(Some(v)) => Some(GistContent {
+                cached: true,
+                ..bincode::deserialize(&v[..]).unwrap()
+            }),
+            _ => None,
+        }
+    }
 
-                self.gists
-                    .insert(&id, bincode::serialize(&gist).unwrap())
-                    .unwrap();
-                gist
+    async fn fetch_gist(&self, gist: &GistRef) -> Option<GistContent> {
+        const URL: &str = "https://api.github.com/gists/";
+        let id = gist.key();
+
+        if self
+            .github_rate_limit
+            .should_back_off(chrono::Utc::now().timestamp())
+        {
+            log::info!("not fetching gist {id} from the API, rate limit is about to be exhausted");
+            return None;
+        }
+
+        let url = format!("{}{}", URL, id);
+        let req = self
+            .client
+            .get(&url)
+            .header(USER_AGENT, "libmedium")
+            .timeout(GIST_TIMEOUT);
+        let resp = crate::github::authenticate(req).send().await;
+        if let Ok(resp) = &resp {
+            self.github_rate_limit.update(resp.headers());
+        }
+        let resp = match resp {
+            Ok(resp) if resp.status().is_success() => resp,
+            Ok(resp) => {
+                log::warn!(
+                    "couldn't fetch gist {id}: GitHub responded with {}",
+                    resp.status()
+                );
+                return None;
+            }
+            Err(e) => {
+                log::warn!("couldn't fetch gist {id}: {e}");
+                return None;
+            }
+        };
+        let resp = match resp.json::<serde_json::Value>().await {
+            Ok(resp) => resp,
+            Err(e) => {
+                log::warn!("couldn't read gist {id}: {e}");
+                return None;
             }
         };
 
-        let gist = if let Some(file_name) = file_name {
-            let mut files: Vec<GistFile> = Vec::with_capacity(1);
-            let mut file = gist
-                .files
-                .iter()
-                .find(|f| f.file_name == file_name)
-                .unwrap()
-                .to_owned();
-            let highlight = render_html::SourcegraphQuery {
-                filepath: &file.file_name,
-                code: &file.content,
-            };
-            file.content = highlight.syntax_highlight(&digest(&file.raw_url));
-            files.push(file);
-            GistContent {
-                files,
-                html_url: gist_url,
+        fn to_gist_file(name: &str, file_obj: &serde_json::Value) -> Option<GistFile> {
+            let field = |key: &str| file_obj.get(key)?.as_str().map(|v| v.to_owned());
+            Some(GistFile {
+                file_name: name.to_string(),
+                content: field("content")?,
+                // GitHub doesn't detect the language of every file
+                language: field("language").unwrap_or_default(),
+                raw_url: field("raw_url")?,
+            })
+        }
+
+        let files = resp
+            .get("files")?
+            .as_object()?
+            .iter()
+            .map(|(name, file_obj)| to_gist_file(name, file_obj))
+            .collect::<Option<Vec<GistFile>>>()?;
+
+        Some(GistContent {
+            files,
+            html_url: resp.get("html_url")?.as_str()?.to_owned(),
+            cached: false,
+        })
+    }
+
+    /// Fetch a gist file from `gist.github.com/{user}/{id}/raw`, which isn't rate-limited
+    /// like the API. GitHub serves the first file of the gist when the embed isn't
+    /// limited to a file.
+    async fn fetch_raw_gist(&self, gist: &GistRef, gist_url: &str) -> Option<GistContent> {
+        let id = gist.key();
+        let mut url = url::Url::parse("https://gist.github.com").unwrap();
+        {
+            let mut segments = url.path_segments_mut().unwrap();
+            segments.clear();
+            if let Some(user) = &gist.user {
+                segments.push(user);
             }
-        } else {
-            let mut index = 1;
-            gist.files.iter_mut().for_each(|f| {
-                let highlight = render_html::SourcegraphQuery {
-                    filepath: &f.file_name,
-                    code: &f.content,
-                };
-                f.content = highlight.syntax_highlight(&digest(&f.raw_url));
-                index += 1;
-            });
-            gist
+            segments.push(&gist.id).push("raw");
+            if let Some(revision) = &gist.revision {
+                segments.push(revision);
+            }
+            if let Some(file_name) = &gist.file {
+                segments.push(file_name);
+            }
+        }
+
+        let resp = self
+            .client
+            .get(url.as_str())
+            .header(USER_AGENT, "libmedium")
+            .timeout(GIST_TIMEOUT)
+            .send()
+            .await
+            .ok()?;
+        if !resp.status().is_success() {
+            log::warn!("couldn't fetch {url}: {}", resp.status());
+            return None;
+        }
+        let content = resp.text().await.ok()?;
+        log::info!("fetched gist {id} from {url}");
+
+        Some(GistContent {
+            files: vec![GistFile {
+                file_name: gist.file.as_deref().unwrap_or(&gist.id).to_owned(),
+                content,
+                language: String::default(),
+                raw_url: url.to_string(),
+            }],
+            html_url: gist_url.to_owned(),
+            cached: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gist_ref_works() {
+        let gist = GistRef::parse(
+            "https://gist.github.com/realaravinth/f2ed2b0c7d5e2b1e4c4d3a0e5b2f7a1c.js?file=main.rs",
+        )
+        .unwrap();
+        assert_eq!(gist.user.as_deref(), Some("realaravinth"));
+        assert_eq!(gist.id, "f2ed2b0c7d5e2b1e4c4d3a0e5b2f7a1c");
+        assert_eq!(gist.revision, None);
+        assert_eq!(gist.file.as_deref(), Some("main.rs"));
+        assert_eq!(gist.key(), "f2ed2b0c7d5e2b1e4c4d3a0e5b2f7a1c");
+
+        let gist = GistRef::parse(
+            "https://gist.github.com/realaravinth/f2ed2b0c7d5e2b1e4c4d3a0e5b2f7a1c/8e1ab0c2d2e9f6a7b8c9d0e1f2a3b4c5d6e7f8a9",
+        )
+        .unwrap();
+        assert_eq!(
+            gist.revision.as_deref(),
+            Some("8e1ab0c2d2e9f6a7b8c9d0e1f2a3b4c5d6e7f8a9")
+        );
+        assert_eq!(
+            gist.key(),
+            "f2ed2b0c7d5e2b1e4c4d3a0e5b2f7a1c/8e1ab0c2d2e9f6a7b8c9d0e1f2a3b4c5d6e7f8a9"
+        );
+
+        let gist = GistRef::parse("https://gist.github.com/2059237").unwrap();
+        assert_eq!(gist.user, None);
+        assert_eq!(gist.id, "2059237");
+
+        assert!(GistRef::parse("https://gist.github.com/realaravinth").is_none());
+        assert!(GistRef::parse("https://gist.github.com/").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn usernames_are_cached() {
+        let data = Data::temporary();
+        let cached = CachedUsername {
+            fetched_at: chrono::Utc::now().timestamp(),
+            username: "tylerneely".into(),
+        };
+        data.user_ids
+            .insert("5145b6327de1", bincode::serialize(&cached).unwrap())
+            .unwrap();
+        assert_eq!(
+            data.get_user_light("5145b6327de1").await.as_deref(),
+            Some("tylerneely")
+        );
+    }
+
+    #[test]
+    fn highlights_are_cleared_when_syntaxes_change() {
+        let data = Data::temporary();
+        let highlight = |code: &str| {
+            let query = render_html::SourcegraphQuery {
+                filepath: "",
+                code,
+                lang: Some("rust"),
+            };
+            data.highlight("key", "code-1", &query)
         };
+        let html = highlight("fn main() {}\n");
+        data.migrate();
+        assert_eq!(highlight("let x = 1;\n"), html);
 
-        (id, gist)
+        data.highlights
+            .insert("HIGHLIGHTS_SYNTAXES", "old syntaxes")
+            .unwrap();
+        data.migrate();
+        assert_ne!(highlight("let x = 1;\n"), html);
+        assert!(data
+            .highlights
+            .get("HIGHLIGHTS_CACHE_VERSION")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn highlights_are_cleared_with_external_assets() {
+        let data = Data::temporary();
+        let asset = data.get_external_asset("https://example.com/cat.png");
+        data.highlights.insert("key", asset.as_str()).unwrap();
+        data.migrate();
+        assert!(data.highlights.get("key").unwrap().is_some());
+
+        data.external_assets
+            .insert(
+                "EXTERNAL_ASSETS_CACHE_VERSION",
+                bincode::serialize(&0usize).unwrap(),
+            )
+            .unwrap();
+        data.migrate();
+        assert_eq!(data.external_assets.len(), 1);
+        assert!(data.highlights.get("key").unwrap().is_none());
+        assert!(data
+            .highlights
+            .get("HIGHLIGHTS_SYNTAXES")
+            .unwrap()
+            .is_some());
+    }
+
+    #[actix_rt::test]
+    async fn only_medium_urls_are_resolved() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let data = Data::temporary();
+        for url in [
+            "http://127.0.0.1/".to_owned(),
+            "http://169.254.169.254/".to_owned(),
+            "http://169.254.169.254/latest/meta-data/".to_owned(),
+            format!("http://127.0.0.1:{port}/"),
+            format!("http://localhost:{port}/"),
+            format!("http://medium.com:{port}/"),
+            "file:///etc/passwd".to_owned(),
+        ] {
+            assert_eq!(data.resolve_post_id(&url).await, None, "{url}");
+        }
+        // nothing was requested
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
     }
 }
@@ -0,0 +1,436 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Third-party embeds(IFRAME paragraphs)
+//!
+//! Embeds are never loaded when a post is opened. Instead, a placeholder is
+//! rendered inside the iframe(using `srcdoc`) and the actual embed is loaded
+//! only when the reader clicks on it, so no JavaScript is required.
+use url::Url;
+
+use crate::post::{escape, RenderContext};
+use crate::SETTINGS;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Provider {
+    YouTube { id: String },
+    Vimeo { id: String },
+    Twitter { path: String },
+    CodePen { user: String, id: String },
+    SoundCloud,
+    Spotify { path: String },
+    Generic,
+}
+
+impl Provider {
+    pub fn classify(href: &str) -> Self {
+        let url = match Url::parse(href) {
+            Ok(url) => url,
+            Err(_) => return Self::Generic,
+        };
+        let host = url.host_str().unwrap_or_default();
+        let host = host.strip_prefix("www.").unwrap_or(host);
+        let host = host.strip_prefix("m.").unwrap_or(host);
+        let segments: Vec<&str> = url
+            .path_segments()
+            .map(|s| s.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+
+        match host {
+            "youtube.com" | "youtube-nocookie.com" => {
+                let id = match segments.first() {
+                    Some(&"watch") => url
+                        .query_pairs()
+                        .find(|(k, _)| k == "v")
+                        .map(|(_, v)| v.into_owned()),
+                    Some(&"embed") | Some(&"shorts") | Some(&"v") | Some(&"live") => {
+                        segments.get(1).map(|id| id.to_string())
+                    }
+                    _ => None,
+                };
+                Self::youtube(id)
+            }
+            "youtu.be" => Self::youtube(segments.first().map(|id| id.to_string())),
+            "vimeo.com" | "player.vimeo.com" => {
+                match segments
+                    .iter()
+                    .find(|s| s.chars().all(|c| c.is_ascii_digit()))
+                {
+                    Some(id) => Self::Vimeo { id: id.to_string() },
+                    None => Self::Generic,
+                }
+            }
+            "twitter.com" | "x.com" if segments.get(1) == Some(&"status") => Self::Twitter {
+                path: url.path().to_owned(),
+            },
+            "codepen.io" if segments.len() >= 3 => Self::CodePen {
+                user: segments[0].to_owned(),
+                id: segments[2].to_owned(),
+            },
+            "soundcloud.com" => Self::SoundCloud,
+            "open.spotify.com" if segments.len() >= 2 => Self::Spotify {
+                path: segments.join("/"),
+            },
+            _ => Self::Generic,
+        }
+    }
+
+    fn youtube(id: Option<String>) -> Self {
+        match id {
+            Some(id)
+                if id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                Self::YouTube { id }
+            }
+            _ => Self::Generic,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::YouTube { .. } => "YouTube",
+            Self::Vimeo { .. } => "Vimeo",
+            Self::Twitter { .. } => "Twitter",
+            Self::CodePen { .. } => "CodePen",
+            Self::SoundCloud => "SoundCloud",
+            Self::Spotify { .. } => "Spotify",
+            Self::Generic => "external",
+        }
+    }
+
+    fn class(&self) -> &'static str {
+        match self {
+            Self::YouTube { .. } => "youtube",
+            Self::Vimeo { .. } => "vimeo",
+            Self::Twitter { .. } => "twitter",
+            Self::CodePen { .. } => "codepen",
+            Self::SoundCloud => "soundcloud",
+            Self::Spotify { .. } => "spotify",
+            Self::Generic => "generic",
+        }
+    }
+
+    /// CSS `aspect-ratio` or height of the iframe
+    fn dimensions(&self) -> &'static str {
+        match self {
+            Self::YouTube { .. } | Self::Vimeo { .. } => "aspect-ratio: 16 / 9",
+            Self::CodePen { .. } => "aspect-ratio: 4 / 3",
+            Self::SoundCloud => "height: 166px",
+            Self::Spotify { .. } => "height: 152px",
+            Self::Twitter { .. } | Self::Generic => "aspect-ratio: 16 / 9",
+        }
+    }
+}
+
+pub struct Embed<'a> {
+    pub provider: Provider,
+    pub href: &'a str,
+    pub iframe_src: Option<&'a str>,
+    pub thumbnail_url: Option<&'a str>,
+    pub title: Option<&'a str>,
+    /// ID of the media page that Medium hosts for this embed
+    pub media_id: Option<&'a str>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+}
+
+impl<'a> Embed<'a> {
+    pub fn new(
+        href: &'a str,
+        iframe_src: Option<&'a str>,
+        thumbnail_url: Option<&'a str>,
+        title: Option<&'a str>,
+    ) -> Self {
+        Self {
+            provider: Provider::classify(href),
+            href,
+            iframe_src,
+            thumbnail_url,
+            title,
+            media_id: None,
+            width: None,
+            height: None,
+        }
+    }
+
+    /// Serve the embed through the instance's media proxy, see [crate::media]
+    pub fn media(mut self, id: &'a str, width: Option<i64>, height: Option<i64>) -> Self {
+        if crate::media::is_media_id(id) {
+            self.media_id = Some(id);
+        }
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// CSS `aspect-ratio` or height of the iframe. Generic embeds use the
+    /// dimensions that Medium stored for them.
+    fn dimensions(&self) -> String {
+        match (&self.provider, self.width, self.height) {
+            (Provider::Generic, Some(w), Some(h)) if w > 0 && h > 0 => {
+                format!("aspect-ratio: {w} / {h}")
+            }
+            (provider, _, _) => provider.dimensions().to_owned(),
+        }
+    }
+
+    /// Page that the reader is sent to when they want to view the embed outside
+    /// of the post. Privacy front-ends are preferred when they are configured.
+    /// `None` when Medium sent something other than a web URL.
+    pub fn link(&self) -> Option<String> {
+        let embeds = &SETTINGS.embeds;
+        match (&self.provider, &embeds.invidious, &embeds.nitter) {
+            (Provider::YouTube { id }, Some(invidious), _) => {
+                Some(format!("{}/watch?v={id}", invidious.trim_end_matches('/')))
+            }
+            (Provider::Twitter { path }, _, Some(nitter)) => {
+                Some(format!("{}{path}", nitter.trim_end_matches('/')))
+            }
+            _ => web_url(self.href).map(|href| href.to_owned()),
+        }
+    }
+
+    /// URL that is loaded in the iframe once the reader clicks on the placeholder
+    pub fn embed_url(&self) -> Option<String> {
+        let embeds = &SETTINGS.embeds;
+        match &self.provider {
+            Provider::YouTube { id } => Some(match &embeds.invidious {
+                Some(invidious) => {
+                    format!("{}/embed/{id}?autoplay=1", invidious.trim_end_matches('/'))
+                }
+                None => format!("https://www.youtube-nocookie.com/embed/{id}?autoplay=1"),
+            }),
+            Provider::Vimeo { id } => Some(format!(
+                "https://player.vimeo.com/video/{id}?dnt=1&autoplay=1"
+            )),
+            Provider::CodePen { user, id } => Some(format!(
+                "https://codepen.io/{user}/embed/{id}?default-tab=result"
+            )),
+            Provider::SoundCloud => {
+                let mut url = Url::parse("https://w.soundcloud.com/player/").unwrap();
+                url.query_pairs_mut().append_pair("url", self.href);
+                Some(url.into())
+            }
+            Provider::Spotify { path } => Some(format!("https://open.spotify.com/embed/{path}")),
+            // Tweets are rendered as links, embedding them requires Twitter's JavaScript
+            Provider::Twitter { .. } => None,
+            Provider::Generic => match self.media_id {
+                Some(id) => Some(crate::V1_API_ROUTES.proxy.get_media(id)),
+                None => self
+                    .iframe_src
+                    .and_then(web_url)
+                    .or_else(|| web_url(self.href))
+                    .map(|src| src.to_owned()),
+            },
+        }
+    }
+
+    fn thumbnail(&self) -> Option<String> {
+        match (&self.provider, self.thumbnail_url) {
+            (_, Some(url)) if !url.is_empty() => Some(url.to_owned()),
+            (Provider::YouTube { id }, _) => {
+                Some(format!("https://i.ytimg.com/vi/{id}/hqdefault.jpg"))
+            }
+            _ => None,
+        }
+    }
+
+    fn host(url: &str) -> String {
+        Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_owned()))
+            .unwrap_or_default()
+    }
+
+    /// Opening tags of the embed block, closed by `</div>`
+    pub fn render(&self, ctx: &dyn RenderContext) -> String {
+        let link = self.link();
+        let class = self.provider.class();
+        let name = self.provider.name();
+
+        let embed_url = match self.embed_url() {
+            Some(url) => url,
+            None => {
+                let title = self.title.unwrap_or("View post");
+                let link = match &link {
+                    Some(link) => format!(
+                        r#"<a rel="noreferrer" href="{}">View on {}</a>"#,
+                        escape(link),
+                        escape(&Self::host(link)),
+                    ),
+                    None => String::default(),
+                };
+                return format!(
+                    r#"<div class="embed embed--{class}"><blockquote><p>{}</p>{link}</blockquote>"#,
+                    escape(title),
+                );
+            }
+        };
+
+        let thumbnail = match self.thumbnail() {
+            Some(url) => format!(
+                r#"<img src="{}" alt="" />"#,
+                escape(&ctx.external_asset(&url))
+            ),
+            None => String::default(),
+        };
+        // embeds served by the instance don't have a host
+        let from = match Self::host(&embed_url) {
+            host if host.is_empty() => String::default(),
+            host => format!(" from {}", escape(&host)),
+        };
+        let placeholder = format!(
+            r#"<!DOCTYPE html><html><head><style>{PLACEHOLDER_CSS}</style></head><body><a href="{}">{thumbnail}<span>&#9654; Load {name} embed{from}</span></a></body></html>"#,
+            escape(&embed_url),
+        );
+
+        let link = match &link {
+            Some(link) => format!(
+                r#"<a class="embed__link" rel="noreferrer" href="{}">Open on {}</a>"#,
+                escape(link),
+                escape(&Self::host(link)),
+            ),
+            None => String::default(),
+        };
+        // scripts are needed by the embeds themselves, the placeholder doesn't have any
+        format!(
+            r#"<div class="embed embed--{class}"><iframe srcdoc="{}" sandbox="allow-scripts allow-popups allow-same-origin" title="{name} embed" style="{}" loading="lazy" allowfullscreen frameborder="0"></iframe>{link}"#,
+            escape(&placeholder),
+            self.dimensions(),
+        )
+    }
+}
+
+const PLACEHOLDER_CSS: &str = "html,body{margin:0;height:100%;font-family:sans-serif}\
+a{display:flex;position:relative;height:100%;align-items:center;justify-content:center;\
+background:#333;color:#fff;text-decoration:none}\
+img{position:absolute;width:100%;height:100%;object-fit:cover;opacity:.6}\
+span{position:relative;padding:10px;background:rgba(0,0,0,.7);border-radius:6px}";
+
+/// `url` if it is an absolute http(s) URL. Medium passes embed URLs through
+/// as they were entered, so anything else(`javascript:`, `data:`) is dropped.
+fn web_url(url: &str) -> Option<&str> {
+    match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Some(url),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::Uncached;
+
+    #[test]
+    fn classify_works() {
+        let youtube = Provider::YouTube {
+            id: "c1gO9aB9nbs".into(),
+        };
+        let embeds = [
+            (
+                "https://www.youtube.com/watch?v=c1gO9aB9nbs",
+                youtube.clone(),
+            ),
+            ("https://youtu.be/c1gO9aB9nbs", youtube.clone()),
+            (
+                "https://www.youtube.com/embed/c1gO9aB9nbs?feature=oembed",
+                youtube,
+            ),
+            (
+                "https://vimeo.com/76979871",
+                Provider::Vimeo {
+                    id: "76979871".into(),
+                },
+            ),
+            (
+                "https://twitter.com/spacejam/status/912969364458377216",
+                Provider::Twitter {
+                    path: "/spacejam/status/912969364458377216".into(),
+                },
+            ),
+            (
+                "https://x.com/spacejam/status/912969364458377216",
+                Provider::Twitter {
+                    path: "/spacejam/status/912969364458377216".into(),
+                },
+            ),
+            (
+                "https://codepen.io/someone/pen/abcdef",
+                Provider::CodePen {
+                    user: "someone".into(),
+                    id: "abcdef".into(),
+                },
+            ),
+            ("https://soundcloud.com/someone/track", Provider::SoundCloud),
+            (
+                "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                Provider::Spotify {
+                    path: "track/4uLU6hMCjMI75M1A2tKUQC".into(),
+                },
+            ),
+            ("https://twitter.com/spacejam", Provider::Generic),
+            ("https://example.com/embed", Provider::Generic),
+        ];
+
+        for (href, provider) in embeds.iter() {
+            assert_eq!(&Provider::classify(href), provider, "{href}");
+        }
+    }
+
+    #[test]
+    fn placeholder_works() {
+        let embed = Embed::new(
+            "https://www.youtube.com/watch?v=c1gO9aB9nbs",
+            None,
+            None,
+            None,
+        );
+        let html = embed.render(&Uncached);
+        assert!(!html.contains("<iframe src="));
+        assert!(html.contains("srcdoc="));
+        assert!(html.contains("/asset/external/"));
+        assert!(html.contains(r#"sandbox="allow-scripts allow-popups allow-same-origin""#));
+    }
+
+    #[test]
+    fn non_web_urls_are_dropped() {
+        let embed = Embed::new(
+            "https://example.com/embed",
+            Some("javascript:alert(1)"),
+            None,
+            None,
+        );
+        assert_eq!(
+            embed.embed_url().as_deref(),
+            Some("https://example.com/embed")
+        );
+        assert!(!embed.render(&Uncached).contains("javascript:"));
+
+        let embed = Embed::new(
+            "javascript:alert(1)",
+            Some("javascript:alert(1)"),
+            None,
+            None,
+        );
+        assert_eq!(embed.link(), None);
+        assert_eq!(embed.embed_url(), None);
+        let html = embed.render(&Uncached);
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("<iframe"));
+    }
+}
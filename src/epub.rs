@@ -0,0 +1,470 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! EPUB 3 export of posts
+//!
+//! Posts are rendered like they are on the instance, sanitized to a subset of
+//! HTML that e-readers support and converted to XHTML. Each post is a chapter;
+//! images are fetched and packaged with the book.
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+
+use actix_web::web::Bytes;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use futures::future::join_all;
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use sailfish::TemplateOnce;
+use sha256::digest;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::data::{Data, PostResp};
+use crate::render_html::{ThemeMode, THEME_CSS};
+use crate::resolve::absolute;
+
+const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="EPUB/package.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+/// Directory that the package document and content are stored in
+const CONTENT_DIR: &str = "EPUB";
+
+/// HTML void elements, which have to be closed in XHTML
+const VOID_ELEMENTS: [&str; 4] = ["img", "br", "hr", "wbr"];
+
+/// Image types that every reading system supports
+const IMAGE_TYPES: [(&str, &str); 5] = [
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/svg+xml", "svg"),
+];
+
+pub struct Chapter {
+    pub file_name: String,
+    pub title: String,
+    pub author: String,
+    pub date: String,
+    pub reading_time: usize,
+    pub source: String,
+    /// XHTML
+    pub body: String,
+}
+
+pub struct Image {
+    pub file_name: String,
+    pub media_type: String,
+    pub bytes: Bytes,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "epub_chapter.xhtml")]
+#[template(rm_whitespace = true)]
+struct ChapterPage<'a> {
+    chapter: &'a Chapter,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "epub_nav.xhtml")]
+#[template(rm_whitespace = true)]
+struct Nav<'a> {
+    title: &'a str,
+    chapters: &'a [Chapter],
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "epub_package.opf")]
+#[template(rm_whitespace = true)]
+struct Package<'a> {
+    identifier: &'a str,
+    title: &'a str,
+    authors: Vec<&'a str>,
+    tags: Vec<&'a str>,
+    modified: &'a str,
+    chapters: &'a [Chapter],
+    images: &'a [Image],
+}
+
+/// Create an EPUB with one chapter per post
+pub async fn build(posts: &[PostResp], data: &Data) -> zip::result::ZipResult<Vec<u8>> {
+    let mut bodies = Vec::with_capacity(posts.len());
+    for post in posts.iter() {
+        let futs: Vec<_> = crate::proxy::code_embed_srcs(post)
+            .into_iter()
+            .map(|src| data.get_code_embed(src))
+            .collect();
+        let gists = if futs.is_empty() {
+            None
+        } else {
+            Some(join_all(futs).await)
+        };
+        let html =
+            crate::post::apply_markup(&post.content.body_model.paragraphs, &gists, data).join("");
+        bodies.push(sanitize(&html));
+    }
+
+    // images are fetched once, even if they are used by several posts
+    let mut srcs: Vec<String> = Vec::default();
+    for body in bodies.iter() {
+        for src in image_srcs(body) {
+            if !srcs.contains(&src) {
+                srcs.push(src);
+            }
+        }
+    }
+    let fetched = join_all(srcs.iter().map(|src| fetch_image(src, data))).await;
+    let mut images: HashMap<String, Image> = HashMap::default();
+    for (src, image) in srcs.into_iter().zip(fetched) {
+        if let Some(image) = image {
+            images.insert(src, image);
+        }
+    }
+
+    let chapters: Vec<Chapter> = posts
+        .iter()
+        .zip(bodies)
+        .enumerate()
+        .map(|(index, (post, body))| Chapter {
+            file_name: format!("chapter-{index}.xhtml"),
+            title: post.title.clone(),
+            author: post.creator.name.clone(),
+            date: crate::proxy::format_date(post.created_at),
+            reading_time: post.reading_time.ceil() as usize,
+            source: post.medium_url.clone().unwrap_or_else(|| {
+                format!(
+                    "https://medium.com/{}/{}",
+                    post.creator.id, post.unique_slug
+                )
+            }),
+            body: to_xhtml(&localize_images(&body, &images)),
+        })
+        .collect();
+    let mut images: Vec<Image> = images.into_values().collect();
+    images.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    package(posts, &chapters, &images)
+}
+
+/// Write the OCF container
+fn package(
+    posts: &[PostResp],
+    chapters: &[Chapter],
+    images: &[Image],
+) -> zip::result::ZipResult<Vec<u8>> {
+    let title = match posts {
+        [post] => post.title.clone(),
+        [post, rest @ ..] => format!("{} and {} more", post.title, rest.len()),
+        [] => "Medium posts".to_owned(),
+    };
+    let identifier = format!(
+        "urn:libmedium:{}",
+        digest(
+            posts
+                .iter()
+                .map(|p| p.unique_slug.as_str())
+                .collect::<Vec<&str>>()
+                .join("\n")
+        )
+    );
+    let modified = posts
+        .iter()
+        .map(|p| p.created_at)
+        .max()
+        .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+    let mut authors: Vec<&str> = Vec::default();
+    let mut tags: Vec<&str> = Vec::default();
+    for post in posts.iter() {
+        if !authors.contains(&post.creator.name.as_str()) {
+            authors.push(&post.creator.name);
+        }
+        for tag in post.tags.iter().flatten() {
+            let tag = tag.display_title.as_deref().unwrap_or(&tag.id);
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+    }
+
+    let package = Package {
+        identifier: &identifier,
+        title: &title,
+        authors,
+        tags,
+        modified: &modified,
+        chapters,
+        images,
+    }
+    .render_once()
+    .unwrap();
+    let nav = Nav {
+        title: &title,
+        chapters,
+    }
+    .render_once()
+    .unwrap();
+    let css = format!(
+        "{}\n{}",
+        include_str!("../templates/epub.css"),
+        THEME_CSS.get(ThemeMode::Light)
+    );
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::default()));
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    // the media type must be the first, uncompressed, file
+    zip.start_file(
+        "mimetype",
+        SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(CONTAINER.as_bytes())?;
+    zip.start_file(format!("{CONTENT_DIR}/package.opf"), deflated)?;
+    zip.write_all(package.as_bytes())?;
+    zip.start_file(format!("{CONTENT_DIR}/nav.xhtml"), deflated)?;
+    zip.write_all(nav.as_bytes())?;
+    zip.start_file(format!("{CONTENT_DIR}/style.css"), deflated)?;
+    zip.write_all(css.as_bytes())?;
+    for chapter in chapters.iter() {
+        let page = ChapterPage { chapter }.render_once().unwrap();
+        zip.start_file(format!("{CONTENT_DIR}/{}", chapter.file_name), deflated)?;
+        zip.write_all(page.as_bytes())?;
+    }
+    // images are already compressed
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for image in images.iter() {
+        zip.start_file(format!("{CONTENT_DIR}/{}", image.file_name), stored)?;
+        zip.write_all(&image.bytes)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Keep elements that e-readers can display. Embeds are reduced to their links.
+fn sanitize(html: &str) -> String {
+    ammonia::Builder::default()
+        .add_generic_attributes(["class", "id"])
+        .link_rel(Some("noopener noreferrer"))
+        .clean(html)
+        .to_string()
+}
+
+fn image_srcs(html: &str) -> Vec<String> {
+    let mut srcs = Vec::default();
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!("img[src]", |el| {
+                srcs.extend(el.get_attribute("src"));
+                Ok(())
+            })],
+            ..RewriteStrSettings::default()
+        },
+    )
+    .unwrap_or_default();
+    srcs
+}
+
+/// Point images to files in the book, images that couldn't be fetched are removed.
+/// Links to the instance are made absolute, except for line numbers of code blocks,
+/// which are made plain text.
+fn localize_images(html: &str, images: &HashMap<String, Image>) -> String {
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("img", |el| {
+                    match el.get_attribute("src").and_then(|src| images.get(&src)) {
+                        Some(image) => el.set_attribute("src", &image.file_name)?,
+                        None => el.remove(),
+                    }
+                    Ok(())
+                }),
+                element!("a.line-number", |el| {
+                    el.remove_attribute("href");
+                    el.remove_attribute("rel");
+                    el.set_tag_name("span")?;
+                    Ok(())
+                }),
+                element!("a[href]:not(.line-number)", |el| {
+                    if let Some(href) = el.get_attribute("href") {
+                        el.set_attribute("href", &absolute(&href))?;
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        },
+    )
+    .unwrap_or_default()
+}
+
+/// Fetch an image that is served by the instance
+async fn fetch_image(src: &str, data: &Data) -> Option<Image> {
+    let proxy = &crate::V1_API_ROUTES.proxy;
+    let url = url::Url::parse("http://localhost")
+        .unwrap()
+        .join(src)
+        .ok()?;
+    let path = url.path();
+    let upstream = if let Some(name) = path.strip_prefix(&proxy.get_medium_asset("")) {
+        let width = url
+            .query_pairs()
+            .find(|(key, _)| key == "w")
+            .and_then(|(_, w)| w.parse().ok())
+            .filter(|w| crate::proxy::ASSET_WIDTHS.contains(w));
+        crate::proxy::miro_url(name, width)
+    } else if let Some(digest) = path.strip_prefix(&proxy.get_external_asset("")) {
+        let url = data.external_assets.get(digest).ok()??;
+        String::from_utf8(url.to_vec()).ok()?
+    } else {
+        log::warn!("image {src} isn't served by the instance");
+        return None;
+    };
+
+    let res = match data.client.get(&upstream).send().await {
+        Ok(res) if res.status().is_success() => res,
+        Ok(res) => {
+            log::warn!("couldn't fetch image {upstream}: {}", res.status());
+            return None;
+        }
+        Err(e) => {
+            log::warn!("couldn't fetch image {upstream}: {e}");
+            return None;
+        }
+    };
+    let media_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)?
+        .to_str()
+        .ok()?
+        .split(';')
+        .next()?
+        .trim()
+        .to_owned();
+    let extension = IMAGE_TYPES
+        .iter()
+        .find(|(type_, _)| *type_ == media_type)
+        .map(|(_, extension)| *extension)?;
+    Some(Image {
+        // image IDs contain characters that aren't allowed in file names, like `*`
+        file_name: format!("images/{}.{extension}", &digest(upstream.as_str())[..16]),
+        media_type,
+        bytes: res.bytes().await.ok()?,
+    })
+}
+
+/// Convert HTML that was serialized by [sanitize] to XHTML: void elements are
+/// closed and `&nbsp;`, the only named entity other than XML's, is made numeric.
+fn to_xhtml(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        output += &rest[..start];
+        rest = &rest[start..];
+        let name_end = rest[1..]
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .map(|i| i + 1)
+            .unwrap_or(rest.len());
+        let is_void = VOID_ELEMENTS.contains(&&rest[1..name_end]);
+        // attribute values are quoted, and can contain `>`
+        let mut in_quote = false;
+        let end = rest
+            .char_indices()
+            .find(|(_, c)| {
+                if *c == '"' {
+                    in_quote = !in_quote;
+                }
+                *c == '>' && !in_quote
+            })
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len() - 1);
+        let tag = &rest[..end];
+        output += tag;
+        if is_void && !tag.ends_with('/') {
+            output += " /";
+        }
+        output.push('>');
+        rest = &rest[end + 1..];
+    }
+    output += rest;
+    output.replace("&nbsp;", "&#160;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xhtml_works() {
+        let html = sanitize(
+            r#"<p>a&nbsp;b<br><img src="/asset/medium/1*a.png" alt="1 > 0"><iframe src="https://example.com"></iframe></p>"#,
+        );
+        assert_eq!(
+            to_xhtml(&html),
+            r#"<p>a&#160;b<br /><img src="/asset/medium/1*a.png" alt="1 &gt; 0" /></p>"#
+        );
+    }
+
+    #[actix_rt::test]
+    async fn epub_works() {
+        let post = crate::proxy::tests::post();
+        let posts = [post.clone(), post];
+        let html = crate::post::apply_markup(
+            &posts[0].content.body_model.paragraphs,
+            &None,
+            Data::temporary().get_ref(),
+        )
+        .join("");
+        let srcs = image_srcs(&sanitize(&html));
+        assert!(srcs.iter().all(|src| src.starts_with("/asset/medium/")));
+
+        let chapters: Vec<Chapter> = (0..2)
+            .map(|index| Chapter {
+                file_name: format!("chapter-{index}.xhtml"),
+                title: posts[index].title.clone(),
+                author: posts[index].creator.name.clone(),
+                date: String::default(),
+                reading_time: 15,
+                source: String::default(),
+                body: to_xhtml(&localize_images(&sanitize(&html), &HashMap::default())),
+            })
+            .collect();
+        assert!(!chapters[0].body.contains("<img"));
+        assert!(chapters[0]
+            .body
+            .contains(r#"<span class="line-number">1</span>"#));
+
+        let epub = package(&posts, &chapters, &[]).unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(epub)).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        let mut opf = String::default();
+        std::io::Read::read_to_string(&mut archive.by_name("EPUB/package.opf").unwrap(), &mut opf)
+            .unwrap();
+        assert!(opf.contains(
+            "<dc:title>Fear and Loathing in Lock-Free Programming and 1 more</dc:title>"
+        ));
+        assert!(opf.contains("<dc:creator>Tyler Neely</dc:creator>"));
+        assert_eq!(opf.matches("<dc:creator>").count(), 1);
+        assert!(opf.contains(r#"<itemref idref="chapter-1" />"#));
+        assert!(archive.by_name("EPUB/chapter-1.xhtml").is_ok());
+    }
+}
@@ -0,0 +1,491 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Gemini frontend
+//!
+//! Serves the index, author pages and posts as gemtext, from the same cache as the
+//! HTTP server. Paths mirror the HTTP routes. Gemtext has no inline formatting, so
+//! links in text are listed as link lines after the paragraph they are in.
+use std::io;
+use std::time::Duration;
+
+use log::{debug, warn};
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_native_tls::TlsAcceptor;
+use url::Url;
+
+use crate::api::{get_document, Block, PostDocument, Span};
+use crate::data::{FetchError, UserResp};
+use crate::proxy::author_posts;
+use crate::resolve::{get_post_id, relative};
+use crate::settings::Gemini;
+use crate::{AppData, Data, SETTINGS};
+
+/// Requests are absolute URLs of at most 1024 bytes, followed by CRLF
+const MAX_REQUEST_LEN: usize = 1024 + 2;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Posts, along with their code embeds, might have to be fetched
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Server {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+}
+
+impl Server {
+    /// Bind to the configured address and load the TLS identity
+    #[cfg(not(tarpaulin_include))]
+    pub fn new(settings: &Gemini) -> io::Result<Self> {
+        let cert = std::fs::read(&settings.cert)?;
+        let key = std::fs::read(&settings.key)?;
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key).map_err(io::Error::other)?;
+        let acceptor = native_tls::TlsAcceptor::new(identity).map_err(io::Error::other)?;
+
+        let listener = std::net::TcpListener::bind(settings.get_ip())?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener: TcpListener::from_std(listener)?,
+            acceptor: acceptor.into(),
+        })
+    }
+
+    #[cfg(not(tarpaulin_include))]
+    pub async fn run(self, data: AppData) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    actix_rt::spawn(handle(stream, self.acceptor.clone(), data.clone()));
+                }
+                Err(e) => warn!("Gemini: couldn't accept connection: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+async fn handle(stream: TcpStream, acceptor: TlsAcceptor, data: AppData) {
+    let mut stream = match acceptor.accept(stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("Gemini: TLS handshake failed: {e}");
+            return;
+        }
+    };
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Some(request)) => {
+            match tokio::time::timeout(RESPONSE_TIMEOUT, respond(&request, &data)).await {
+                Ok(response) => response,
+                Err(_) => Response::TemporaryFailure("Timed out"),
+            }
+        }
+        _ => Response::BadRequest("Invalid request"),
+    };
+    if let Err(e) = stream.write_all(response.to_string().as_bytes()).await {
+        debug!("Gemini: couldn't write response: {e}");
+    }
+    let _ = stream.shutdown().await;
+}
+
+async fn read_request<S: AsyncReadExt + Unpin>(stream: &mut S) -> Option<String> {
+    let mut request = Vec::with_capacity(MAX_REQUEST_LEN);
+    let mut buf = [0; MAX_REQUEST_LEN];
+    while !request.ends_with(b"\r\n") {
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return None;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    request.truncate(request.len() - 2);
+    String::from_utf8(request).ok()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Response {
+    Input(&'static str),
+    Success(String),
+    Redirect(String),
+    NotFound(&'static str),
+    BadRequest(&'static str),
+    /// Building the response took too long
+    TemporaryFailure(&'static str),
+    /// Medium couldn't be reached
+    ProxyError(&'static str),
+}
+
+impl From<FetchError> for Response {
+    fn from(e: FetchError) -> Self {
+        match e {
+            FetchError::NotFound => Self::NotFound("Post not found"),
+            FetchError::Upstream => Self::ProxyError("Couldn't fetch post from Medium"),
+        }
+    }
+}
+
+impl std::fmt::Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Input(prompt) => write!(f, "10 {prompt}\r\n"),
+            Self::Success(body) => write!(f, "20 text/gemini; charset=utf-8\r\n{body}"),
+            Self::Redirect(url) => write!(f, "30 {url}\r\n"),
+            Self::NotFound(msg) => write!(f, "51 {msg}\r\n"),
+            Self::BadRequest(msg) => write!(f, "59 {msg}\r\n"),
+            Self::TemporaryFailure(msg) => write!(f, "40 {msg}\r\n"),
+            Self::ProxyError(msg) => write!(f, "43 {msg}\r\n"),
+        }
+    }
+}
+
+async fn respond(request: &str, data: &Data) -> Response {
+    let url = match Url::parse(request) {
+        Ok(url) if url.scheme() == "gemini" => url,
+        _ => return Response::BadRequest("Only gemini:// URLs are served"),
+    };
+    let segments: Vec<&str> = url.path().split('/').filter(|s| !s.is_empty()).collect();
+    let proxy = &crate::V1_API_ROUTES.proxy;
+
+    match segments.as_slice() {
+        [] => Response::Success(index()),
+        ["resolve"] => {
+            let query = match url.query() {
+                Some(query) => percent_decode_str(query).decode_utf8_lossy(),
+                None => return Response::Input("URL of a Medium post"),
+            };
+            let post_id = match get_post_id(&query) {
+                Some(post_id) => Some(post_id),
+                None => data.resolve_post_id(&query).await,
+            };
+            match post_id {
+                Some(post_id) => redirect_to_post(&post_id, data).await,
+                None => Response::NotFound("Couldn't find a Medium post at this URL"),
+            }
+        }
+        ["utils", "post", post] => match get_post_id(post) {
+            Some(post_id) => redirect_to_post(&post_id, data).await,
+            None => Response::BadRequest("Invalid post ID"),
+        },
+        ["utils", "user", user] => match data.get_user_light(user).await {
+            Some(username) => Response::Redirect(proxy.get_author(&username)),
+            None => Response::NotFound("User not found"),
+        },
+        [author] if author.starts_with('@') => match data.get_user(&author[1..]).await {
+            Some(user) => Response::Success(author_page(&user)),
+            None => Response::NotFound("User not found"),
+        },
+        [post] => match get_post_id(post) {
+            Some(post_id) => redirect_to_post(&post_id, data).await,
+            None => Response::NotFound("Post not found"),
+        },
+        [_, post] => match get_post_id(post) {
+            Some(post_id) => match get_document(&post_id, data).await {
+                Ok(doc) => Response::Success(render(&doc)),
+                Err(e) => e.into(),
+            },
+            None => Response::NotFound("Post not found"),
+        },
+        _ => Response::NotFound("Not found"),
+    }
+}
+
+async fn redirect_to_post(post_id: &str, data: &Data) -> Response {
+    match data.get_post_light(post_id).await {
+        Ok(post_data) => Response::Redirect(
+            crate::V1_API_ROUTES
+                .proxy
+                .get_page(&post_data.username, &post_data.slug),
+        ),
+        Err(e) => e.into(),
+    }
+}
+
+fn index() -> String {
+    format!(
+        "# libmedium\n\nPrivacy-focused proxy for medium.com\n\n\
+         => {} Read a Medium post\n=> {} Source code\n",
+        crate::V1_API_ROUTES.proxy.resolve,
+        SETTINGS.source_code
+    )
+}
+
+fn author_page(user: &UserResp) -> String {
+    let mut lines = vec![format!("# {}", user.name), String::default()];
+    if let Some(bio) = user.bio.as_ref().filter(|bio| !bio.is_empty()) {
+        lines.push(escape_lines(bio));
+        lines.push(String::default());
+    }
+    lines.push(format!(
+        "=> https://medium.com/@{} Open profile in medium.com",
+        user.username
+    ));
+    for post in author_posts(user) {
+        lines.push(String::default());
+        lines.push(format!("=> {} {}", post.url, post.title));
+        if !post.subtitle.is_empty() {
+            lines.push(escape_lines(&post.subtitle));
+        }
+        lines.push(format!("{} · {} min read", post.date, post.reading_time));
+    }
+    lines.join("\n") + "\n"
+}
+
+pub fn render(doc: &PostDocument) -> String {
+    let mut blocks = vec![format!(
+        "# {}\n\n{} · {}\n=> {} {}\n=> {} Read on the web",
+        doc.title,
+        doc.author.name,
+        &doc.created_at[..10],
+        relative(&doc.author.url),
+        doc.author.name,
+        doc.url
+    )];
+    for block in doc.blocks.iter() {
+        let gemtext = match block {
+            Block::Heading { level, spans } => {
+                // the title is the only top level heading, posts use H3 for sections
+                let marker = if *level <= 3 { "##" } else { "###" };
+                with_links(format!("{marker} {}", text(spans)), &[spans])
+            }
+            Block::Paragraph { spans } => with_links(escape_lines(&text(spans)), &[spans]),
+            Block::Quote { spans, .. } => {
+                let quote = escape_lines(&text(spans))
+                    .lines()
+                    .map(|line| format!("> {line}"))
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                with_links(quote, &[spans])
+            }
+            Block::List { ordered, items } => {
+                let list = items
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| {
+                        // list lines can't wrap and gemtext has no ordered lists
+                        let item = text(item).lines().collect::<Vec<&str>>().join(" ");
+                        if *ordered {
+                            format!("{}. {item}", index + 1)
+                        } else {
+                            format!("* {item}")
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                let items: Vec<&[Span]> = items.iter().map(|item| item.as_slice()).collect();
+                with_links(list, &items)
+            }
+            Block::Image { url, caption, .. } => match text(caption) {
+                caption if caption.is_empty() => format!("=> {url} Image"),
+                caption => format!("=> {url} Image: {caption}"),
+            },
+            Block::Code { language, code } => preformatted(language.as_deref(), code),
+            Block::CodeEmbed { url, host, files } => match files {
+                Some(files) => files
+                    .iter()
+                    .map(|file| preformatted(Some(&file.name), &file.content))
+                    .chain(std::iter::once(format!("=> {url} View on {host}")))
+                    .collect::<Vec<String>>()
+                    .join("\n\n"),
+                None => format!("=> {url} Code on {host}"),
+            },
+            Block::Embed { url, title } => {
+                format!("=> {url} Embed: {}", title.as_deref().unwrap_or(url))
+            }
+            Block::Link {
+                url,
+                title,
+                description,
+            } => match description {
+                Some(description) => {
+                    format!(
+                        "=> {} {title}\n{}",
+                        relative(url),
+                        escape_lines(description)
+                    )
+                }
+                None => format!("=> {} {title}", relative(url)),
+            },
+        };
+        blocks.push(gemtext);
+    }
+    blocks.join("\n\n") + "\n"
+}
+
+fn text(spans: &[Span]) -> String {
+    spans.iter().map(|span| span.text.as_str()).collect()
+}
+
+/// Follow `gemtext` with link lines for the links in `spans`
+fn with_links(mut gemtext: String, spans: &[&[Span]]) -> String {
+    let mut links: Vec<(&str, String)> = Vec::default();
+    for spans in spans {
+        let mut prev: Option<&str> = None;
+        for span in spans.iter() {
+            match span.link.as_deref() {
+                // a link can span several spans with different formatting
+                Some(url) if prev == Some(url) => links.last_mut().unwrap().1 += &span.text,
+                Some(url) => links.push((url, span.text.clone())),
+                None => (),
+            }
+            prev = span.link.as_deref();
+        }
+    }
+    for (url, text) in links {
+        gemtext += &format!("\n=> {} {}", relative(url), text.trim());
+    }
+    gemtext
+}
+
+/// The type of a line is determined by how it starts. Lines of text that would be
+/// mistaken for links, headings, list items, quotes or preformatting toggles are
+/// prefixed with a space.
+fn escape_lines(text: &str) -> String {
+    const MARKERS: [&str; 5] = ["=>", "#", "* ", ">", "```"];
+    text.lines()
+        .map(|line| match MARKERS.iter().any(|m| line.starts_with(m)) {
+            true => format!(" {line}"),
+            false => line.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn preformatted(alt: Option<&str>, code: &str) -> String {
+    // only toggles end preformatted blocks
+    let code = code
+        .trim_end_matches('\n')
+        .lines()
+        .map(|line| match line.starts_with("```") {
+            true => format!(" {line}"),
+            false => line.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    format!("```{}\n{code}\n```", alt.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gemtext_works() {
+        let post = crate::proxy::tests::post();
+        let doc = crate::api::document("7158b1cdd50c", &post, &[]);
+        let gemtext = render(&doc);
+        let base_url = SETTINGS.server.get_base_url();
+        assert!(gemtext.starts_with(&format!(
+            "# Fear and Loathing in Lock-Free Programming\n\nTyler Neely · 2017-09-27\n\
+             => /@tylerneely Tyler Neely\n=> {base_url}/tylerneely/"
+        )));
+        assert!(gemtext.contains(
+            "who actually know how to write them.\n=> http://www.1024cores.net/ two\n\
+             => /utils/user/5145b6327de1 people\n"
+        ));
+        assert!(gemtext.contains("## Lock-Free 101\n\n1. Create a node\n2. Read"));
+        assert!(gemtext.contains("=> /utils/post/b62607a43a8c Big Data, Small Effort"));
+        assert!(gemtext.contains(
+            "\n```rust\nfn cas(&self, old: usize, new: usize) -> usize {\n    self.0.compare_and_swap"
+        ));
+        assert!(gemtext.contains("> Don’t write lock-free algorithms. You will die."));
+        assert!(gemtext.contains(&format!(
+            "=> {base_url}/asset/medium/1*MbItv9J5KMGqnpMlUmgQZA.jpeg Image: Spin until successful"
+        )));
+        // preformatted blocks are closed
+        assert_eq!(gemtext.matches("```").count() % 2, 0);
+    }
+
+    #[test]
+    fn gemtext_is_escaped() {
+        let mut post = crate::proxy::tests::post();
+        let paragraphs = &mut post.content.body_model.paragraphs;
+        // a fence inside a code block, Markdown in a code block for example
+        let mut fence = paragraphs[14].clone();
+        fence.text = "```".into();
+        paragraphs.insert(15, fence);
+        let mut text = paragraphs[16].clone();
+        text.type_ = "P".into();
+        text.text = "=> /evil Not a link\n# Not a heading\n* Not a list item\n```".into();
+        paragraphs.insert(17, text);
+        paragraphs[18].text = "```\n=> /evil Not a link".into();
+
+        let gemtext = render(&crate::api::document("7158b1cdd50c", &post, &[]));
+        assert!(gemtext.contains("compare_and_swap(old, new, Ordering::SeqCst)\n ```\n}\n```\n"));
+        assert!(gemtext
+            .contains("\n => /evil Not a link\n # Not a heading\n * Not a list item\n ```\n"));
+        assert!(gemtext.contains("\n>  ```\n>  => /evil Not a link"));
+        assert!(!gemtext.contains("\n=> /evil"));
+        // only the toggles of the code block start lines
+        assert_eq!(
+            gemtext
+                .lines()
+                .filter(|line| line.starts_with("```"))
+                .count(),
+            2
+        );
+    }
+
+    #[actix_rt::test]
+    async fn requests_work() {
+        let data = Data::temporary();
+        let index = respond("gemini://localhost/", &data).await;
+        assert!(matches!(index, Response::Success(body) if body.starts_with("# libmedium")));
+        assert_eq!(
+            respond("gemini://localhost/resolve", &data).await,
+            Response::Input("URL of a Medium post")
+        );
+        assert_eq!(
+            respond("https://localhost/", &data).await,
+            Response::BadRequest("Only gemini:// URLs are served")
+        );
+        assert_eq!(
+            respond("gemini://localhost/utils/post/not-a-post", &data).await,
+            Response::BadRequest("Invalid post ID")
+        );
+        assert_eq!(
+            respond("gemini://localhost/a/b/c", &data).await,
+            Response::NotFound("Not found")
+        );
+        assert_eq!(
+            Response::Redirect("/@tylerneely".into()).to_string(),
+            "30 /@tylerneely\r\n"
+        );
+        assert_eq!(
+            Response::from(FetchError::NotFound).to_string(),
+            "51 Post not found\r\n"
+        );
+        assert_eq!(
+            Response::from(FetchError::Upstream).to_string(),
+            "43 Couldn't fetch post from Medium\r\n"
+        );
+        assert_eq!(
+            Response::TemporaryFailure("Timed out").to_string(),
+            "40 Timed out\r\n"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn requests_are_read() {
+        let mut request: &[u8] = b"gemini://localhost/\r\n";
+        assert_eq!(
+            read_request(&mut request).await.as_deref(),
+            Some("gemini://localhost/")
+        );
+        let long = format!("gemini://localhost/{}\r\n", "a".repeat(MAX_REQUEST_LEN));
+        assert_eq!(read_request(&mut long.as_bytes()).await, None);
+        assert_eq!(read_request(&mut &b"gemini://localhost/"[..]).await, None);
+    }
+}
@@ -0,0 +1,355 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Type-aware rendering of gist files
+//!
+//! Markdown files are rendered to sanitized HTML, Jupyter notebooks are rendered
+//! cell by cell and everything else is syntax highlighted.
+use std::path::Path;
+
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use pulldown_cmark::{html::push_html, Options, Parser};
+use sailfish::runtime::escape::escape_to_string;
+use serde::Deserialize;
+use url::Url;
+
+use crate::data::GistFile;
+use crate::post::RenderContext;
+use crate::render_html::SourcegraphQuery;
+use crate::resolve::rewrite_link;
+
+const MARKDOWN_EXTENSIONS: [&str; 4] = ["md", "markdown", "mdown", "mkd"];
+
+fn extension(file_name: &str) -> String {
+    Path::new(file_name)
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Markdown,
+    Notebook,
+    Code,
+}
+
+impl Kind {
+    pub fn classify(file: &GistFile) -> Self {
+        let extension = extension(&file.file_name);
+        if extension == "ipynb" || file.language == "Jupyter Notebook" {
+            Self::Notebook
+        } else if MARKDOWN_EXTENSIONS.contains(&extension.as_str()) || file.language == "Markdown" {
+            Self::Markdown
+        } else {
+            Self::Code
+        }
+    }
+}
+
+/// Content type of raw gist files. Files are never served as HTML(or any other
+/// type that browsers would run scripts in).
+pub fn content_type(file_name: &str) -> &'static str {
+    match extension(file_name).as_str() {
+        "json" => "application/json",
+        "ipynb" => "application/x-ipynb+json",
+        x if MARKDOWN_EXTENSIONS.contains(&x) => "text/markdown; charset=utf-8",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+/// Render a gist file. `id` is used to derive IDs of code blocks and must be
+/// made of lowercase alphanumeric characters and dashes.
+pub fn render(file: &GistFile, id: &str, ctx: &dyn RenderContext) -> String {
+    let code = || {
+        SourcegraphQuery {
+            filepath: &file.file_name,
+            code: &file.content,
+            lang: None,
+        }
+        .syntax_highlight(id)
+    };
+    match Kind::classify(file) {
+        Kind::Markdown => format!(
+            r#"<div class="gist-markdown">{}</div>"#,
+            markdown(&file.content, ctx)
+        ),
+        // not every `.ipynb` file is a valid notebook
+        Kind::Notebook => notebook(&file.content, id, ctx).unwrap_or_else(code),
+        Kind::Code => code(),
+    }
+}
+
+/// Render Markdown to sanitized HTML. Images are proxied and links to Medium
+/// are rewritten to point to the instance.
+pub fn markdown(md: &str, ctx: &dyn RenderContext) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let mut html = String::default();
+    push_html(&mut html, Parser::new_ext(md, options));
+
+    let html = ammonia::Builder::default()
+        .link_rel(Some("noopener noreferrer"))
+        .clean(&html)
+        .to_string();
+
+    rewrite_str(
+        &html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("img", |el| {
+                    match el
+                        .get_attribute("src")
+                        .and_then(|src| proxy_image(&src, ctx))
+                    {
+                        Some(src) => el.set_attribute("src", &src)?,
+                        None => el.remove(),
+                    }
+                    Ok(())
+                }),
+                element!("a[href]", |el| {
+                    if let Some(href) = el.get_attribute("href") {
+                        el.set_attribute("href", &rewrite_link(&href))?;
+                    }
+                    el.set_attribute("target", "_blank")?;
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        },
+    )
+    .unwrap_or_default()
+}
+
+/// Instance URL of an image. Relative URLs can't be resolved, so they are dropped.
+fn proxy_image(src: &str, ctx: &dyn RenderContext) -> Option<String> {
+    let url = Url::parse(src)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))?;
+    let rewritten = crate::media::rewrite_asset(url.as_str())?;
+    if rewritten == url.as_str() {
+        Some(ctx.external_asset(url.as_str()))
+    } else {
+        // hosted by Medium
+        Some(rewritten)
+    }
+}
+
+/// Notebook source and text outputs are either strings or lists of lines
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Text {
+    Text(String),
+    Lines(Vec<String>),
+}
+
+impl Text {
+    fn join(&self) -> String {
+        match self {
+            Self::Text(text) => text.to_owned(),
+            Self::Lines(lines) => lines.concat(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Notebook {
+    cells: Vec<Cell>,
+    #[serde(default)]
+    metadata: NotebookMetadata,
+}
+
+#[derive(Deserialize, Default)]
+struct NotebookMetadata {
+    language_info: Option<LanguageInfo>,
+    kernelspec: Option<KernelSpec>,
+}
+
+#[derive(Deserialize)]
+struct LanguageInfo {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct KernelSpec {
+    language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Cell {
+    cell_type: String,
+    source: Text,
+    #[serde(default)]
+    outputs: Vec<Output>,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    output_type: String,
+    text: Option<Text>,
+    data: Option<OutputData>,
+    ename: Option<String>,
+    evalue: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OutputData {
+    #[serde(rename = "text/plain")]
+    text: Option<Text>,
+}
+
+impl Output {
+    /// Text of the output. Rich outputs(images, HTML, etc.) aren't rendered.
+    fn text(&self) -> Option<String> {
+        match self.output_type.as_str() {
+            "stream" => self.text.as_ref().map(|t| t.join()),
+            "execute_result" | "display_data" => {
+                self.data.as_ref()?.text.as_ref().map(|t| t.join())
+            }
+            // tracebacks are full of terminal escape codes
+            "error" => Some(format!(
+                "{}: {}",
+                self.ename.as_deref().unwrap_or_default(),
+                self.evalue.as_deref().unwrap_or_default()
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Render a Jupyter notebook(nbformat 4). Returns `None` if `json` isn't a notebook.
+pub fn notebook(json: &str, id: &str, ctx: &dyn RenderContext) -> Option<String> {
+    let notebook: Notebook = serde_json::from_str(json).ok()?;
+    let lang = notebook
+        .metadata
+        .language_info
+        .map(|l| l.name)
+        .or_else(|| notebook.metadata.kernelspec.and_then(|k| k.language));
+
+    let mut html = String::from(r#"<div class="notebook">"#);
+    for (index, cell) in notebook.cells.iter().enumerate() {
+        let source = cell.source.join();
+        match cell.cell_type.as_str() {
+            "markdown" => {
+                html.push_str(r#"<div class="notebook__cell notebook__cell--markdown">"#);
+                html.push_str(&markdown(&source, ctx));
+            }
+            "code" => {
+                html.push_str(r#"<div class="notebook__cell notebook__cell--code">"#);
+                let query = SourcegraphQuery {
+                    filepath: "",
+                    code: &source,
+                    lang: lang.as_deref(),
+                };
+                html.push_str(&query.syntax_highlight(&format!("{id}-{}", index + 1)));
+                for output in cell.outputs.iter().filter_map(|o| o.text()) {
+                    html.push_str(r#"<pre class="notebook__output">"#);
+                    escape_to_string(&output, &mut html);
+                    html.push_str("</pre>");
+                }
+            }
+            _ => {
+                html.push_str(r#"<div class="notebook__cell notebook__cell--raw"><pre>"#);
+                escape_to_string(&source, &mut html);
+                html.push_str("</pre>");
+            }
+        }
+        html.push_str("</div>");
+    }
+    html.push_str("</div>");
+    Some(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::Uncached;
+
+    fn file(file_name: &str, language: &str, content: &str) -> GistFile {
+        GistFile {
+            file_name: file_name.into(),
+            content: content.into(),
+            language: language.into(),
+            raw_url: format!("https://gist.githubusercontent.com/a/b/raw/c/{file_name}"),
+        }
+    }
+
+    #[test]
+    fn content_type_works() {
+        assert_eq!(content_type("main.rs"), "text/plain; charset=utf-8");
+        assert_eq!(content_type("index.html"), "text/plain; charset=utf-8");
+        assert_eq!(content_type("README.MD"), "text/markdown; charset=utf-8");
+    }
+
+    #[test]
+    fn markdown_works() {
+        let md = r#"# Title
+
+Read [the post](https://medium.com/p/7158b1cdd50c) <script>alert(1)</script>
+
+![img](https://example.com/cat.png) ![medium](https://miro.medium.com/max/1400/1*LY2ohYsNa9nOV1Clko3zJA.png) ![rel](cat.png)
+"#;
+        let html = render(&file("README.md", "Markdown", md), "foo", &Uncached);
+        assert!(html.starts_with(r#"<div class="gist-markdown"><h1>Title</h1>"#));
+        assert!(!html.contains("<script"));
+        assert!(html.contains(r#"href="/utils/post/7158b1cdd50c""#));
+        assert!(html.contains(r#"src="/asset/medium/1*LY2ohYsNa9nOV1Clko3zJA.png""#));
+        assert!(html.contains(&format!(
+            r#"src="{}""#,
+            Uncached.external_asset("https://example.com/cat.png")
+        )));
+        assert!(!html.contains("example.com"));
+        assert!(!html.contains("cat.png\""));
+    }
+
+    #[test]
+    fn notebook_works() {
+        let nb = r##"{
+  "cells": [
+    {"cell_type": "markdown", "metadata": {}, "source": ["# Notebook\n", "Some *text*"]},
+    {"cell_type": "code", "execution_count": 1, "metadata": {}, "source": "def add(a, b):\n    return a + b\nadd(1, 2) < 4",
+     "outputs": [
+       {"output_type": "stream", "name": "stdout", "text": ["printed\n"]},
+       {"output_type": "execute_result", "execution_count": 1, "metadata": {}, "data": {"text/plain": ["True"], "image/png": "iVBORw0KGgo="}},
+       {"output_type": "error", "ename": "ZeroDivisionError", "evalue": "division by zero", "traceback": ["\u001b[0;31m"]}
+     ]}
+  ],
+  "metadata": {"language_info": {"name": "python"}},
+  "nbformat": 4,
+  "nbformat_minor": 5
+}"##;
+        let html = render(&file("nb.ipynb", "Jupyter Notebook", nb), "foo", &Uncached);
+        assert!(html.starts_with(r#"<div class="notebook">"#));
+        assert!(html.contains("<h1>Notebook</h1>"));
+        assert!(html.contains("<em>text</em>"));
+        assert!(html.contains(r#"<pre class="syn-code" id="foo-2">"#));
+        // highlighted as python
+        assert!(html.contains("syn-python"));
+        assert!(html.contains(r#"<pre class="notebook__output">printed"#));
+        assert!(html.contains(r#"<pre class="notebook__output">True</pre>"#));
+        assert!(html.contains("ZeroDivisionError: division by zero"));
+        assert!(!html.contains("iVBORw0KGgo"));
+
+        // invalid notebooks are highlighted as code
+        let html = render(&file("nb.ipynb", "Jupyter Notebook", "{"), "foo", &Uncached);
+        assert!(html.starts_with(r#"<pre class="syn-code" id="foo">"#));
+        let html = render(&file("main.rs", "Rust", "fn main() {}"), "foo", &Uncached);
+        assert!(html.contains("syn-rust"));
+    }
+}
@@ -0,0 +1,112 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! GitHub API access(used to fetch gists)
+//!
+//! Anonymous requests are limited to 60 requests per hour per IP, so requests
+//! are authenticated when a token is configured and the instance stops using
+//! the API when it's about to run out of requests.
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use reqwest::header::HeaderMap;
+use reqwest::RequestBuilder;
+
+use crate::SETTINGS;
+
+/// Requests that are left alone, so that the instance doesn't run into the limit
+/// when requests are made concurrently
+const RESERVE: i64 = 5;
+
+/// Add credentials to API requests, if a token is configured
+pub fn authenticate(req: RequestBuilder) -> RequestBuilder {
+    let req = req.header(reqwest::header::ACCEPT, "application/vnd.github+json");
+    match SETTINGS.github.token.as_ref() {
+        Some(token) => req.bearer_auth(token),
+        None => req,
+    }
+}
+
+/// Rate limit of the instance, as last reported by GitHub
+#[derive(Debug)]
+pub struct RateLimit {
+    /// `-1` until the first response is received
+    remaining: AtomicI64,
+    /// Unix time at which the limit resets
+    reset: AtomicI64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            remaining: AtomicI64::new(-1),
+            reset: AtomicI64::new(0),
+        }
+    }
+}
+
+impl RateLimit {
+    /// Update from `X-RateLimit-Remaining` and `X-RateLimit-Reset` headers
+    pub fn update(&self, headers: &HeaderMap) {
+        let header = |name: &str| -> Option<i64> { headers.get(name)?.to_str().ok()?.parse().ok() };
+        if let (Some(remaining), Some(reset)) =
+            (header("x-ratelimit-remaining"), header("x-ratelimit-reset"))
+        {
+            self.remaining.store(remaining, Ordering::Relaxed);
+            self.reset.store(reset, Ordering::Relaxed);
+            if remaining <= RESERVE {
+                log::warn!(
+                    "GitHub API rate limit is about to be exhausted, backing off until {reset}"
+                );
+            }
+        }
+    }
+
+    /// Whether the API should be avoided until the limit resets
+    pub fn should_back_off(&self, now: i64) -> bool {
+        let remaining = self.remaining.load(Ordering::Relaxed);
+        (0..=RESERVE).contains(&remaining) && now < self.reset.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_works() {
+        let limit = RateLimit::default();
+        assert!(!limit.should_back_off(100));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", "42".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "200".parse().unwrap());
+        limit.update(&headers);
+        assert!(!limit.should_back_off(100));
+
+        headers.insert(
+            "x-ratelimit-remaining",
+            RESERVE.to_string().parse().unwrap(),
+        );
+        limit.update(&headers);
+        assert!(limit.should_back_off(100));
+        // the limit has been reset
+        assert!(!limit.should_back_off(200));
+
+        // responses without rate limit headers don't change anything
+        limit.update(&HeaderMap::new());
+        assert!(limit.should_back_off(100));
+    }
+}
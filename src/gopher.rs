@@ -0,0 +1,544 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Gopher frontend
+//!
+//! Posts are served as plain text documents, wrapped for narrow terminals. Links
+//! in posts are numbered and listed in a menu at `/{username}/{post}/links`.
+//! Author pages are menus. Selectors mirror the HTTP routes.
+use std::io;
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::api::{get_document, Block, PostDocument, Span};
+use crate::data::{FetchError, UserResp};
+use crate::proxy::author_posts;
+use crate::resolve::{get_post_id, relative};
+use crate::settings::Gopher;
+use crate::text::indent;
+use crate::{AppData, Data, SETTINGS};
+
+const MAX_REQUEST_LEN: usize = 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Posts, along with their code embeds, might have to be fetched
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+const WIDTH: usize = 70;
+const INDENT: &str = "    ";
+
+pub struct Server {
+    listener: TcpListener,
+}
+
+impl Server {
+    #[cfg(not(tarpaulin_include))]
+    pub fn new(settings: &Gopher) -> io::Result<Self> {
+        let listener = std::net::TcpListener::bind(settings.get_ip())?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener: TcpListener::from_std(listener)?,
+        })
+    }
+
+    #[cfg(not(tarpaulin_include))]
+    pub async fn run(self, data: AppData) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    actix_rt::spawn(handle(stream, data.clone()));
+                }
+                Err(e) => warn!("Gopher: couldn't accept connection: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+async fn handle(mut stream: TcpStream, data: AppData) {
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Some(request)) => {
+            match tokio::time::timeout(RESPONSE_TIMEOUT, respond(&request, &data)).await {
+                Ok(response) => response,
+                Err(_) => error("Timed out"),
+            }
+        }
+        _ => error("Invalid request"),
+    };
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("Gopher: couldn't write response: {e}");
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Read a selector line. Lines should end with CRLF, but some clients only send LF.
+async fn read_request<S: AsyncReadExt + Unpin>(stream: &mut S) -> Option<String> {
+    let mut request = Vec::with_capacity(MAX_REQUEST_LEN);
+    let mut buf = [0; MAX_REQUEST_LEN];
+    while !request.ends_with(b"\n") {
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return None;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8(request).ok()?;
+    Some(request.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+async fn respond(request: &str, data: &Data) -> String {
+    // search queries follow the selector after a tab, Gopher+ clients add more fields
+    let mut fields = request.split('\t');
+    let selector = fields.next().unwrap_or_default();
+    let query = fields.next();
+    let segments: Vec<&str> = selector.split('/').filter(|s| !s.is_empty()).collect();
+
+    match segments.as_slice() {
+        [] => index(),
+        ["resolve"] => {
+            let query = match query {
+                Some(query) => query,
+                None => return error("Search for the URL of a Medium post"),
+            };
+            let post_id = match get_post_id(query) {
+                Some(post_id) => Some(post_id),
+                None => data.resolve_post_id(query).await,
+            };
+            match post_id {
+                Some(post_id) => links(&post_id, data).await,
+                None => error("Couldn't find a Medium post at this URL"),
+            }
+        }
+        ["utils", "post", post] => post_text(post, data).await,
+        ["utils", "user", user] => match data.get_user_light(user).await {
+            Some(username) => author(&username, data).await,
+            None => error("User not found"),
+        },
+        [author_name] if author_name.starts_with('@') => author(&author_name[1..], data).await,
+        [post] | [_, post] => post_text(post, data).await,
+        [_, post, "links"] => match get_post_id(post) {
+            Some(post_id) => links(&post_id, data).await,
+            None => error("Post not found"),
+        },
+        _ => error("Not found"),
+    }
+}
+
+async fn post_text(post: &str, data: &Data) -> String {
+    let post_id = match get_post_id(post) {
+        Some(post_id) => post_id,
+        None => return error("Post not found"),
+    };
+    match get_document(&post_id, data).await {
+        Ok(doc) => text_document(&render(&doc).0),
+        Err(e) => fetch_error(e),
+    }
+}
+
+async fn links(post_id: &str, data: &Data) -> String {
+    match get_document(post_id, data).await {
+        Ok(doc) => links_menu(&doc),
+        Err(e) => fetch_error(e),
+    }
+}
+
+async fn author(username: &str, data: &Data) -> String {
+    match data.get_user(username).await {
+        Some(user) => author_menu(&user),
+        None => error("User not found"),
+    }
+}
+
+fn index() -> String {
+    let mut menu = info("libmedium");
+    menu += &info("Privacy-focused proxy for medium.com");
+    menu += &info("");
+    menu += &item(
+        '7',
+        "Read a Medium post",
+        crate::V1_API_ROUTES.proxy.resolve,
+    );
+    menu += &item('h', "Source code", &format!("URL:{}", SETTINGS.source_code));
+    menu + ".\r\n"
+}
+
+fn author_menu(user: &UserResp) -> String {
+    let mut menu = info(&user.name);
+    menu += &info("");
+    if let Some(bio) = user.bio.as_ref().filter(|bio| !bio.is_empty()) {
+        menu += &info_lines(&wrap(bio, WIDTH));
+        menu += &info("");
+    }
+    menu += &item(
+        'h',
+        "Open profile in medium.com",
+        &format!("URL:https://medium.com/@{}", user.username),
+    );
+    for post in author_posts(user) {
+        menu += &info("");
+        menu += &item('0', &post.title, &post.url);
+        if !post.subtitle.is_empty() {
+            menu += &info_lines(&wrap(&post.subtitle, WIDTH));
+        }
+        menu += &info(&format!("{} · {} min read", post.date, post.reading_time));
+        menu += &item('1', "Links in this post", &format!("{}/links", post.url));
+    }
+    menu + ".\r\n"
+}
+
+/// Menu with the links in a post, numbered like the references in its text
+fn links_menu(doc: &PostDocument) -> String {
+    let (_, references) = render(doc);
+    let mut menu = info_lines(&wrap(&doc.title, WIDTH));
+    menu += &info("");
+    menu += &item('0', "Read the post", &relative(&doc.url));
+    menu += &item('1', &doc.author.name, &relative(&doc.author.url));
+    menu += &item('h', "Read on the web", &format!("URL:{}", doc.url));
+    if !references.is_empty() {
+        menu += &info("");
+        menu += &info("Links in the post:");
+    }
+    for (index, (url, label)) in references.iter().enumerate() {
+        menu += &link(&format!("[{}] {label}", index + 1), url);
+    }
+    menu + ".\r\n"
+}
+
+/// Render a post as wrapped text, links are replaced with numbered references.
+/// Returns the text and the URLs and labels of the references.
+pub fn render(doc: &PostDocument) -> (String, Vec<(String, String)>) {
+    let mut refs = References::default();
+    let title = wrap(&doc.title, WIDTH);
+    let title_width = title.lines().map(|l| l.chars().count()).max();
+    let mut blocks = vec![
+        format!("{title}\n{}", "=".repeat(title_width.unwrap_or_default())),
+        format!(
+            "{} · {}\n{}",
+            doc.author.name,
+            &doc.created_at[..10],
+            doc.url
+        ),
+    ];
+    for block in doc.blocks.iter() {
+        let text = match block {
+            Block::Heading { level, spans } => {
+                let heading = wrap(&refs.text(spans), WIDTH);
+                let width = heading.lines().map(|l| l.chars().count()).max();
+                // underlined like Setext headings, posts use H3 for section titles
+                match level {
+                    1..=3 => format!("{heading}\n{}", "-".repeat(width.unwrap_or_default())),
+                    _ => heading,
+                }
+            }
+            Block::Paragraph { spans } => wrap(&refs.text(spans), WIDTH),
+            Block::Quote { spans, .. } => indent(&wrap(&refs.text(spans), WIDTH - 2), "  "),
+            Block::List { ordered, items } => items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    let marker = if *ordered {
+                        format!("{}. ", index + 1)
+                    } else {
+                        "- ".to_owned()
+                    };
+                    let item = wrap(&refs.text(item), WIDTH - marker.len());
+                    let item = indent(&item, &" ".repeat(marker.len()));
+                    format!("{marker}{}", item.trim_start())
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Block::Image { url, caption, .. } => {
+                let caption = refs.text(caption);
+                let label = if caption.is_empty() {
+                    "Image"
+                } else {
+                    &caption
+                };
+                let index = refs.add(url, label);
+                match caption.as_str() {
+                    "" => format!("[Image] [{index}]"),
+                    caption => wrap(&format!("[Image: {caption}] [{index}]"), WIDTH),
+                }
+            }
+            Block::Code { code, .. } => indent(code.trim_end_matches('\n'), INDENT),
+            Block::CodeEmbed { url, host, files } => {
+                let link = format!("[Code on {host}] [{}]", refs.add(url, url));
+                match files {
+                    Some(files) => files
+                        .iter()
+                        .map(|file| {
+                            format!(
+                                "{}:\n\n{}",
+                                file.name,
+                                indent(file.content.trim_end_matches('\n'), INDENT)
+                            )
+                        })
+                        .chain(std::iter::once(link))
+                        .collect::<Vec<String>>()
+                        .join("\n\n"),
+                    None => link,
+                }
+            }
+            Block::Embed { url, title } => {
+                let title = title.as_deref().unwrap_or(url);
+                wrap(
+                    &format!("[Embed: {title}] [{}]", refs.add(url, title)),
+                    WIDTH,
+                )
+            }
+            Block::Link {
+                url,
+                title,
+                description,
+            } => {
+                let link = format!("[{title}] [{}]", refs.add(url, title));
+                match description {
+                    Some(description) => wrap(&format!("{link}\n{description}"), WIDTH),
+                    None => wrap(&link, WIDTH),
+                }
+            }
+        };
+        blocks.push(text);
+    }
+    if !refs.0.is_empty() {
+        let links = refs
+            .0
+            .iter()
+            .enumerate()
+            .map(|(index, (url, _))| format!("[{}] {url}", index + 1))
+            .collect::<Vec<String>>()
+            .join("\n");
+        blocks.push(format!("Links:\n\n{links}"));
+    }
+    let mut text = blocks.join("\n\n");
+    text.push('\n');
+    (text, refs.0)
+}
+
+/// URLs and labels of the links in a post
+#[derive(Default)]
+struct References(Vec<(String, String)>);
+
+impl References {
+    /// Number of the reference to `url`, starting at 1
+    fn add(&mut self, url: &str, label: &str) -> usize {
+        match self.0.iter().position(|(u, _)| u == url) {
+            Some(index) => index + 1,
+            None => {
+                self.0.push((url.to_owned(), label.to_owned()));
+                self.0.len()
+            }
+        }
+    }
+
+    /// Text of `spans`, with references after links
+    fn text(&mut self, spans: &[Span]) -> String {
+        let mut text = String::default();
+        let mut label = String::default();
+        let mut spans = spans.iter().peekable();
+        while let Some(span) = spans.next() {
+            text += &span.text;
+            if let Some(link) = &span.link {
+                label += &span.text;
+                // a link can span several spans with different formatting
+                if spans.peek().and_then(|next| next.link.as_ref()) != Some(link) {
+                    text += &format!(" [{}]", self.add(link, label.trim()));
+                    label.clear();
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Wrap lines of `text` at word boundaries. Words that are longer than `width` are
+/// left as they are.
+fn wrap(text: &str, width: usize) -> String {
+    text.lines()
+        .map(|line| {
+            let mut lines: Vec<String> = Vec::default();
+            let mut current = String::default();
+            for word in line.split_whitespace() {
+                if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > width
+                {
+                    lines.push(std::mem::take(&mut current));
+                }
+                if !current.is_empty() {
+                    current.push(' ');
+                }
+                current += word;
+            }
+            lines.push(current);
+            lines.join("\n")
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn text_document(text: &str) -> String {
+    let mut document: String = text
+        .lines()
+        .map(|line| {
+            // a line with a single dot ends the document
+            if line.starts_with('.') {
+                format!(".{line}\r\n")
+            } else {
+                format!("{line}\r\n")
+            }
+        })
+        .collect();
+    document += ".\r\n";
+    document
+}
+
+fn item(kind: char, display: &str, selector: &str) -> String {
+    let port = SETTINGS
+        .gopher
+        .as_ref()
+        .map_or_else(Gopher::default_port, |gopher| gopher.port);
+    // tabs separate fields and line breaks end items
+    let clean = |field: &str| field.replace(|c: char| c.is_control(), " ");
+    format!(
+        "{kind}{}\t{}\t{}\t{port}\r\n",
+        clean(display),
+        clean(selector),
+        SETTINGS.server.domain
+    )
+}
+
+fn info(text: &str) -> String {
+    item('i', text, "")
+}
+
+fn info_lines(text: &str) -> String {
+    text.lines().map(info).collect()
+}
+
+/// Item for a link in a post. Posts and authors on this instance are served over
+/// Gopher, other links are URL links.
+fn link(display: &str, url: &str) -> String {
+    let path = relative(url);
+    if !path.starts_with('/') {
+        return item('h', display, &format!("URL:{url}"));
+    }
+    let proxy = &crate::V1_API_ROUTES.proxy;
+    if path.starts_with("/@") || path.starts_with(&proxy.get_by_user_id("")) {
+        item('1', display, &path)
+    } else {
+        item('0', display, &path)
+    }
+}
+
+fn error(msg: &str) -> String {
+    format!("3{msg}\t\terror.host\t1\r\n.\r\n")
+}
+
+fn fetch_error(e: FetchError) -> String {
+    match e {
+        FetchError::NotFound => error("Post not found"),
+        FetchError::Upstream => error("Couldn't fetch post from Medium"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc() -> PostDocument {
+        let post = crate::proxy::tests::post();
+        crate::api::document("7158b1cdd50c", &post, &[])
+    }
+
+    #[test]
+    fn text_works() {
+        let (text, references) = render(&doc());
+        // only code and words that are longer than a line, like URLs, aren't wrapped
+        assert!(text.lines().all(|line| line.chars().count() <= WIDTH
+            || line.starts_with(INDENT)
+            || !line.contains(' ')
+            || line.starts_with('[')));
+        assert!(text.starts_with(
+            "Fear and Loathing in Lock-Free Programming\n==========================================\n\nTyler Neely · 2017-09-27\n"
+        ));
+        assert!(text.contains("there are only two [2] people [3] who actually\nknow"));
+        assert_eq!(
+            references[1],
+            ("http://www.1024cores.net/".into(), "two".into())
+        );
+        assert!(text.contains("\n[2] http://www.1024cores.net/\n"));
+        assert!(text.contains(
+            "\n    fn cas(&self, old: usize, new: usize) -> usize {\n        self.0.compare_and_swap"
+        ));
+    }
+
+    #[test]
+    fn menus_work() {
+        let menu = links_menu(&doc());
+        let domain = &SETTINGS.server.domain;
+        assert!(menu.starts_with(&format!(
+            "iFear and Loathing in Lock-Free Programming\t\t{domain}\t70\r\n"
+        )));
+        assert!(menu.contains(&format!(
+            "0Read the post\t/tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c\t{domain}\t70\r\n"
+        )));
+        assert!(menu.contains(&format!(
+            "h[2] two\tURL:http://www.1024cores.net/\t{domain}\t70\r\n"
+        )));
+        assert!(menu.contains(&format!(
+            "1[3] people\t/utils/user/5145b6327de1\t{domain}\t70\r\n"
+        )));
+        assert!(menu.ends_with("\r\n.\r\n"));
+        assert_eq!(
+            item('0', "a\tb\r\n3c", "/d\n.\r"),
+            format!("0a b  3c\t/d . \t{domain}\t70\r\n")
+        );
+        assert_eq!(text_document(".\nfoo\n"), "..\r\nfoo\r\n.\r\n");
+    }
+
+    #[actix_rt::test]
+    async fn requests_work() {
+        let data = Data::temporary();
+        assert!(respond("", &data).await.starts_with("ilibmedium\t"));
+        assert!(respond("/", &data)
+            .await
+            .contains("7Read a Medium post\t/resolve\t"));
+        assert_eq!(
+            respond("/resolve", &data).await,
+            error("Search for the URL of a Medium post")
+        );
+        assert_eq!(respond("/a/b/c/d", &data).await, error("Not found"));
+        assert_eq!(
+            respond("/utils/post/foo", &data).await,
+            error("Post not found")
+        );
+        assert_eq!(fetch_error(FetchError::NotFound), error("Post not found"));
+        assert!(fetch_error(FetchError::Upstream).starts_with("3Couldn't fetch post"));
+
+        let mut request: &[u8] = b"/@tylerneely\n";
+        assert_eq!(
+            read_request(&mut request).await.as_deref(),
+            Some("/@tylerneely")
+        );
+        assert_eq!(
+            read_request(&mut &b"/resolve\tfoo\r\n"[..])
+                .await
+                .as_deref(),
+            Some("/resolve\tfoo")
+        );
+        assert_eq!(read_request(&mut &b"/"[..]).await, None);
+    }
+}
@@ -23,13 +23,25 @@ use actix_web::{
 use lazy_static::lazy_static;
 use log::info;
 
+mod api;
+mod code_embed;
 mod data;
+mod embed;
+mod epub;
+mod gemini;
+mod gist;
+mod github;
+mod gopher;
+mod markdown;
+mod media;
 mod meta;
 mod post;
 mod proxy;
 mod render_html;
+mod resolve;
 mod routes;
 mod settings;
+mod text;
 
 pub use data::AppData;
 pub use data::Data;
@@ -63,6 +75,21 @@ async fn main() -> std::io::Result<()> {
     println!("Starting server on: http://{}", SETTINGS.server.get_ip());
 
     let data = Data::new();
+    lazy_static::initialize(&render_html::SYNTAX_SET);
+    // fail early if the configured syntax themes don't exist
+    lazy_static::initialize(&render_html::THEME_CSS);
+
+    if let Some(settings) = SETTINGS.gemini.as_ref() {
+        let server = gemini::Server::new(settings)?;
+        println!("Starting Gemini server on: gemini://{}", settings.get_ip());
+        actix_rt::spawn(server.run(data.clone()));
+    }
+
+    if let Some(settings) = SETTINGS.gopher.as_ref() {
+        let server = gopher::Server::new(settings)?;
+        println!("Starting Gopher server on: gopher://{}", settings.get_ip());
+        actix_rt::spawn(server.run(data.clone()));
+    }
 
     HttpServer::new(move || {
         App::new()
@@ -0,0 +1,607 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Markdown(CommonMark) export of posts
+//!
+//! Posts are exported with YAML front matter. Images either point to this
+//! instance or to files that are bundled with the post in a zip archive.
+use std::io::{Cursor, Write};
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+use futures::future::join_all;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::code_embed::CodeEmbed;
+use crate::data::{Data, GistContent, Paragraph, PostResp};
+use crate::proxy::StringUtils;
+use crate::render_html::SourcegraphQuery;
+use crate::resolve::absolute;
+
+/// Directory that images are stored in, in bundles
+const IMAGES_DIR: &str = "images";
+
+/// Where image links point to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Images {
+    /// The `/asset/medium/` route of this instance
+    Instance,
+    /// Files in the archive created by [bundle]
+    Bundle,
+}
+
+impl Images {
+    /// Parse the `images` query parameter, which defaults to [Images::Instance]
+    pub fn from_query(images: Option<&str>) -> Option<Self> {
+        match images {
+            None | Some("instance") => Some(Self::Instance),
+            Some("bundle") => Some(Self::Bundle),
+            Some(_) => None,
+        }
+    }
+}
+
+pub struct Markdown {
+    pub text: String,
+    /// IDs of the Medium images that the post uses
+    pub images: Vec<String>,
+}
+
+/// Render a post. `code_embeds` are unrendered, see [Data::get_code_embed_source].
+pub fn render(
+    post: &PostResp,
+    code_embeds: &Option<Vec<(String, Option<GistContent>)>>,
+    images: Images,
+) -> Markdown {
+    let mut renderer = Renderer {
+        code_embeds,
+        images,
+        used_images: Vec::default(),
+    };
+    let mut text = front_matter(post);
+    text += &format!("# {}\n", escape(&post.title));
+    text += &renderer.blocks(&post.content.body_model.paragraphs);
+    Markdown {
+        text,
+        images: renderer.used_images,
+    }
+}
+
+/// Zip archive with the post(`{slug}.md`) and its images. Images that can't be
+/// fetched are left out.
+pub async fn bundle(
+    markdown: &Markdown,
+    slug: &str,
+    data: &Data,
+) -> zip::result::ZipResult<Vec<u8>> {
+    let images = join_all(markdown.images.iter().map(|id| fetch_image(id, data))).await;
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::default()));
+    zip.start_file(
+        format!("{slug}.md"),
+        SimpleFileOptions::default().compression_method(CompressionMethod::Deflated),
+    )?;
+    zip.write_all(markdown.text.as_bytes())?;
+    // images are already compressed
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for (id, bytes) in markdown.images.iter().zip(images) {
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        zip.start_file(format!("{IMAGES_DIR}/{id}"), stored)?;
+        zip.write_all(&bytes)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+async fn fetch_image(id: &str, data: &Data) -> Option<actix_web::web::Bytes> {
+    let res = data
+        .client
+        .get(crate::proxy::miro_url(id, None))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    match res {
+        Ok(res) => res.bytes().await.ok(),
+        Err(e) => {
+            log::warn!("couldn't bundle image {id}: {e}");
+            None
+        }
+    }
+}
+
+/// Title, author, date, canonical URL and tags of the post. Strings are quoted as
+/// JSON, which is valid YAML.
+fn front_matter(post: &PostResp) -> String {
+    let quote = |s: &str| serde_json::to_string(s).unwrap();
+    let date = Utc
+        .timestamp_millis_opt(post.created_at)
+        .unwrap()
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+    let canonical_url = post
+        .canonical_url
+        .as_deref()
+        .filter(|url| !url.is_empty())
+        .or(post.medium_url.as_deref());
+    let tags: Vec<String> = post
+        .tags
+        .iter()
+        .flatten()
+        .map(|tag| quote(tag.display_title.as_deref().unwrap_or(&tag.id)))
+        .collect();
+
+    let mut front_matter = format!(
+        "---\ntitle: {}\nauthor: {}\ndate: {date}\n",
+        quote(&post.title),
+        quote(&post.creator.name)
+    );
+    if let Some(url) = canonical_url {
+        front_matter += &format!("canonical_url: {}\n", quote(url));
+    }
+    front_matter += &format!("tags: [{}]\n---\n\n", tags.join(", "));
+    front_matter
+}
+
+struct Renderer<'a> {
+    code_embeds: &'a Option<Vec<(String, Option<GistContent>)>>,
+    images: Images,
+    used_images: Vec<String>,
+}
+
+impl<'a> Renderer<'a> {
+    fn blocks(&mut self, paragraphs: &[Paragraph]) -> String {
+        let mut output = String::default();
+        let mut prev_type = "";
+        let mut list_number = 0;
+        // consecutive PRE paragraphs are merged into one code block, like in HTML
+        let mut code_block_end = 0;
+        for (pindex, p) in paragraphs.iter().enumerate() {
+            // the title, which is rendered from the post's metadata
+            if p.type_ == "H3" && pindex == 0 {
+                continue;
+            }
+            if pindex < code_block_end {
+                continue;
+            }
+
+            let type_ = p.type_.as_str();
+            list_number = if type_ == "OLI" && prev_type == "OLI" {
+                list_number + 1
+            } else {
+                1
+            };
+            let block = match type_ {
+                "H1" | "H2" | "H3" | "H4" | "H5" | "H6" => {
+                    let level: usize = type_[1..].parse().unwrap();
+                    format!("{} {}", "#".repeat(level), inline(p).replace("\\\n", " "))
+                }
+                "IMG" => self.img(p),
+                "PRE" => {
+                    code_block_end = pindex
+                        + paragraphs[pindex..]
+                            .iter()
+                            .take_while(|p| p.type_ == "PRE")
+                            .count();
+                    code_block(&paragraphs[pindex..code_block_end])
+                }
+                "BQ" | "PQ" => quote(&inline(p)),
+                "ULI" => indent("- ", &inline(p)),
+                "OLI" => indent(&format!("{list_number}. "), &inline(p)),
+                "IFRAME" => self.iframe(p),
+                "MIXTAPE_EMBED" => mixtape(p).unwrap_or_else(|| inline(p)),
+                _ => inline(p),
+            };
+
+            if !output.is_empty() {
+                // list items are kept together
+                let tight = type_ == prev_type && (type_ == "ULI" || type_ == "OLI");
+                output += if tight { "\n" } else { "\n\n" };
+            } else {
+                output += "\n";
+            }
+            output += &block;
+            prev_type = type_;
+        }
+        output.push('\n');
+        output
+    }
+
+    fn img(&mut self, p: &Paragraph) -> String {
+        let id = &p.metadata.as_ref().unwrap().id;
+        if !self.used_images.contains(id) {
+            self.used_images.push(id.to_owned());
+        }
+        let src = match self.images {
+            Images::Instance => absolute(&crate::V1_API_ROUTES.proxy.get_medium_asset(id)),
+            Images::Bundle => format!("{IMAGES_DIR}/{id}"),
+        };
+        format!(
+            "![{}]({})",
+            escape(&p.text).replace('\n', " "),
+            destination(&src)
+        )
+    }
+
+    /// Code embeds are inlined as code blocks, other embeds are linked to
+    fn iframe(&self, p: &Paragraph) -> String {
+        let media = p.iframe.as_ref().unwrap().media_resource.as_ref().unwrap();
+        let src = &media.href;
+        let code = match CodeEmbed::classify(src) {
+            Some(code) => code,
+            None => {
+                let title = media.title.as_deref().filter(|t| !t.is_empty());
+                return format!("[{}]({})", escape(title.unwrap_or(src)), destination(src));
+            }
+        };
+
+        let content = self
+            .code_embeds
+            .as_ref()
+            .and_then(|embeds| embeds.iter().find(|(url, _)| url == src))
+            .and_then(|(_, content)| content.as_ref());
+        let link = match &code {
+            CodeEmbed::Gist(_) => "See gist on GitHub".to_owned(),
+            _ => format!("See on {}", code.host()),
+        };
+        match content {
+            Some(content) => {
+                let mut blocks: Vec<String> = content
+                    .files
+                    .iter()
+                    .map(|file| {
+                        let lang = SourcegraphQuery {
+                            filepath: &file.file_name,
+                            code: &file.content,
+                            lang: None,
+                        }
+                        .language();
+                        fence(&file.content, lang.as_deref())
+                    })
+                    .collect();
+                blocks.push(format!("[{link}]({})", destination(&content.html_url)));
+                blocks.join("\n\n")
+            }
+            None => format!(
+                "[{} on {}]({})",
+                escape(&code.file_name()),
+                code.host(),
+                destination(src)
+            ),
+        }
+    }
+}
+
+/// Medium stores each code block as consecutive PRE paragraphs, with the language
+/// of the block in the first one. Blocks without a language are detected.
+fn code_block(block: &[Paragraph]) -> String {
+    let code = block
+        .iter()
+        .fold(String::default(), |code, p| code + &p.text + "\n");
+    let lang = block[0]
+        .code_block_metadata
+        .as_ref()
+        .and_then(|m| m.lang.as_deref());
+    let lang = SourcegraphQuery {
+        filepath: "",
+        code: &code,
+        lang,
+    }
+    .language();
+    fence(&code, lang.as_deref())
+}
+
+/// Fenced code block, with a fence that is longer than any backtick run in `code`
+fn fence(code: &str, lang: Option<&str>) -> String {
+    let fence = "`".repeat(longest_backtick_run(code).max(2) + 1);
+    let code = code.strip_suffix('\n').unwrap_or(code);
+    format!("{fence}{}\n{code}\n{fence}", lang.unwrap_or_default())
+}
+
+fn mixtape(p: &Paragraph) -> Option<String> {
+    let mixtape = p.mixtape_metadata.as_ref()?;
+    let title = mixtape
+        .media_resource
+        .as_ref()
+        .and_then(|r| r.title.clone())
+        .unwrap_or_else(|| {
+            // older posts don't have the resource, the title is the first line
+            p.text.lines().next().unwrap_or_default().to_owned()
+        });
+    Some(format!(
+        "[{}]({})",
+        escape(&title),
+        destination(&link(&mixtape.href))
+    ))
+}
+
+/// Text of a paragraph with links, emphasis and inline code
+fn inline(p: &Paragraph) -> String {
+    let len = p.text.chars().count();
+    // (start, end, opening delimiter, closing delimiter)
+    let mut spans: Vec<(usize, usize, String, String)> = Vec::default();
+    for m in p.markups.iter() {
+        let (start, end) = (m.start as usize, (m.end as usize).min(len));
+        if start >= end {
+            continue;
+        }
+        match m.type_.as_str() {
+            "STRONG" | "EM" => {
+                // delimiters next to whitespace don't open or close emphasis
+                let text = p.text.slice(start..end);
+                let leading = text.chars().take_while(|c| c.is_whitespace()).count();
+                let trailing = text.chars().rev().take_while(|c| c.is_whitespace()).count();
+                if leading == end - start {
+                    continue;
+                }
+                let delimiter = if m.type_ == "STRONG" { "**" } else { "*" };
+                spans.push((
+                    start + leading,
+                    end - trailing,
+                    delimiter.into(),
+                    delimiter.into(),
+                ));
+            }
+            "CODE" => {
+                let code = p.text.slice(start..end);
+                let ticks = "`".repeat(longest_backtick_run(code) + 1);
+                let pad = if code.starts_with('`') || code.ends_with('`') {
+                    " "
+                } else {
+                    ""
+                };
+                spans.push((start, end, format!("{ticks}{pad}"), format!("{pad}{ticks}")));
+            }
+            "A" => {
+                let href = match (m.anchor_type.as_deref(), &m.href, &m.user_id) {
+                    (Some("LINK"), Some(href), _) => link(href),
+                    (Some("USER"), _, Some(user_id)) => {
+                        absolute(&crate::V1_API_ROUTES.proxy.get_by_user_id(user_id))
+                    }
+                    _ => continue,
+                };
+                spans.push((start, end, "[".into(), format!("]({})", destination(&href))));
+            }
+            _ => (),
+        }
+    }
+
+    let mut output = String::with_capacity(p.text.len());
+    let mut code_end = None;
+    let mut line_start = true;
+    let mut digits = 0;
+    for (index, c) in p.text.chars().chain(std::iter::once('\n')).enumerate() {
+        // closing delimiters of inner spans come first
+        let mut closing: Vec<&(usize, usize, String, String)> =
+            spans.iter().filter(|s| s.1 == index).collect();
+        closing.sort_by_key(|s| std::cmp::Reverse(s.0));
+        for span in closing {
+            output += &span.3;
+        }
+        if code_end == Some(index) {
+            code_end = None;
+        }
+        if index == len {
+            break;
+        }
+
+        let mut opening: Vec<&(usize, usize, String, String)> =
+            spans.iter().filter(|s| s.0 == index).collect();
+        opening.sort_by_key(|s| std::cmp::Reverse(s.1));
+        for span in opening {
+            // markups inside inline code can't be rendered
+            if code_end.is_some() {
+                continue;
+            }
+            if span.2.starts_with('`') {
+                code_end = Some(span.1);
+            }
+            output += &span.2;
+        }
+
+        if code_end.is_some() {
+            output.push(c);
+        } else if c == '\n' {
+            // hard line break
+            output += "\\\n";
+        } else if needs_escape(c, line_start, digits) {
+            output.push('\\');
+            output.push(c);
+        } else {
+            output.push(c);
+        }
+        digits = if c.is_ascii_digit() && (line_start || digits > 0) {
+            digits + 1
+        } else {
+            0
+        };
+        line_start = c == '\n';
+    }
+    output
+}
+
+/// Characters that would be parsed as Markdown. `digits` is the number of digits
+/// that the current line starts with, which would make `1.` an ordered list.
+fn needs_escape(c: char, line_start: bool, digits: usize) -> bool {
+    match c {
+        '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '&' | '|' | '~' => true,
+        '#' | '-' | '+' | '=' => line_start,
+        '.' | ')' => digits > 0,
+        _ => false,
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    for (index, c) in text.chars().enumerate() {
+        if needs_escape(c, index == 0, 0) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+    output
+}
+
+/// Links to Medium are rewritten to point to this instance
+fn link(href: &str) -> String {
+    absolute(&crate::resolve::rewrite_link(href))
+}
+
+/// Link destination, in angle brackets when it would end the link early
+fn destination(url: &str) -> String {
+    if url.contains([' ', '(', ')']) {
+        format!("<{}>", url.replace('<', "%3C").replace('>', "%3E"))
+    } else {
+        url.to_owned()
+    }
+}
+
+fn quote(text: &str) -> String {
+    text.lines()
+        .map(|line| format!("> {line}").trim_end().to_owned())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Indent continuation lines of list items
+fn indent(marker: &str, text: &str) -> String {
+    let padding = " ".repeat(marker.len());
+    let mut lines = text.lines();
+    let mut output = format!("{marker}{}", lines.next().unwrap_or_default());
+    for line in lines {
+        output += &format!("\n{padding}{line}");
+    }
+    output
+}
+
+fn longest_backtick_run(code: &str) -> usize {
+    code.split(|c| c != '`')
+        .map(|run| run.len())
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::GistFile;
+    use crate::proxy::tests::post;
+    use crate::SETTINGS;
+
+    #[test]
+    fn markdown_works() {
+        let post = post();
+        let md = render(&post, &None, Images::Instance);
+        assert!(md.text.starts_with(
+            "---\ntitle: \"Fear and Loathing in Lock-Free Programming\"\nauthor: \"Tyler Neely\"\ndate: 2017-09-27T17:05:11Z\ncanonical_url: \"https://medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c\"\ntags: [\"Programming\", \"Rust\", \"Concurrency\"]\n---\n\n# Fear and Loathing in Lock-Free Programming\n"
+        ));
+        assert!(md.text.contains(
+            "```rust\nfn cas(&self, old: usize, new: usize) -> usize {\n    self.0.compare_and_swap(old, new, Ordering::SeqCst)\n}\n```"
+        ));
+        assert!(md
+            .text
+            .contains("1. Create a node\n2. Read the current `stack.head` and set"));
+        assert!(md
+            .text
+            .contains("> Don’t write lock-free algorithms. You will die."));
+        assert!(md.text.contains(&format!(
+            "![Spin until successful]({}/asset/medium/",
+            SETTINGS.server.get_base_url()
+        )));
+        assert!(md
+            .text
+            .contains("[Lock-free programming talk](https://www.youtube.com/watch?v=c1gO9aB9nbs)"));
+        assert!(md.text.contains(&format!(
+            "[Big Data, Small Effort]({}/utils/post/b62607a43a8c)",
+            SETTINGS.server.get_base_url()
+        )));
+        assert!(!md.images.is_empty());
+
+        let bundled = render(&post, &None, Images::Bundle);
+        assert!(bundled.text.contains("![Spin until successful](images/"));
+        assert_eq!(bundled.images, md.images);
+    }
+
+    #[test]
+    fn code_embeds_work() {
+        let mut post = post();
+        const FILE: &str =
+            "https://github.com/realaravinth/libmedium/blob/8e1ab0c/src/data.rs#L1-L2";
+        const PASTE: &str = "https://pastebin.com/UIFdu235";
+        let mut iframes = post
+            .content
+            .body_model
+            .paragraphs
+            .iter_mut()
+            .filter(|p| p.type_ == "IFRAME");
+        for src in [FILE, PASTE] {
+            iframes
+                .next()
+                .unwrap()
+                .iframe
+                .as_mut()
+                .unwrap()
+                .media_resource
+                .as_mut()
+                .unwrap()
+                .href = src.into();
+        }
+        let content = GistContent {
+            files: vec![GistFile {
+                file_name: "data.rs".into(),
+                content: "/// ```\nfn main() {}\n".into(),
+                language: String::default(),
+                raw_url: String::default(),
+            }],
+            html_url: FILE.into(),
+            cached: false,
+        };
+        let code_embeds = Some(vec![
+            (FILE.to_owned(), Some(content)),
+            (PASTE.to_owned(), None),
+        ]);
+
+        let md = render(&post, &code_embeds, Images::Instance);
+        assert!(md.text.contains(&format!(
+            "````rust\n/// ```\nfn main() {{}}\n````\n\n[See on GitHub]({FILE})"
+        )));
+        assert!(md
+            .text
+            .contains(&format!("[UIFdu235 on Pastebin]({PASTE})")));
+    }
+
+    #[actix_rt::test]
+    async fn bundle_works() {
+        let md = Markdown {
+            text: "# Hello\n".into(),
+            images: Vec::default(),
+        };
+        let zip = bundle(&md, "hello-123", &Data::temporary()).await.unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        let mut text = String::default();
+        std::io::Read::read_to_string(&mut archive.by_name("hello-123.md").unwrap(), &mut text)
+            .unwrap();
+        assert_eq!(text, md.text);
+    }
+
+    #[test]
+    fn escape_works() {
+        assert_eq!(escape("# 1 * 2 = [2]"), r"\# 1 \* 2 = \[2\]");
+        assert_eq!(escape("2021. A year"), "2021. A year");
+        assert!(needs_escape('.', false, 4));
+        assert_eq!(fence("a\n", None), "```\na\n```");
+    }
+}
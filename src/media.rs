@@ -0,0 +1,201 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Medium-hosted embeds(`https://medium.com/media/{id}`)
+//!
+//! Media pages are fetched by the instance and sanitized before they are
+//! served: scripts and event handlers are removed, images are proxied and
+//! links to Medium are rewritten to point to the instance.
+use lol_html::html_content::Element;
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use url::Url;
+
+use crate::resolve::{is_medium, rewrite_link};
+use crate::V1_API_ROUTES;
+
+/// Content-Security-Policy of sanitized media pages. Scripts are never allowed;
+/// nested iframes are, since that's how most embeds work.
+pub const CSP: &str = concat!(
+    "default-src 'none'; img-src 'self' data:; style-src 'self' 'unsafe-inline'; ",
+    "font-src 'self'; media-src 'self'; frame-src https:; base-uri 'none'; form-action 'none'"
+);
+
+/// Medium media IDs are 32 character long lowercase hex strings
+pub fn is_media_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Rewrite images hosted by Medium to the asset route. `None` is returned
+/// for URLs that point to Medium but aren't images(tracking pixels, etc.)
+pub fn rewrite_asset(src: &str) -> Option<String> {
+    let url = match Url::parse(src) {
+        Ok(url) => url,
+        // relative URLs would resolve to this instance
+        Err(_) => return Some(src.to_owned()),
+    };
+    match url.host_str() {
+        Some("miro.medium.com") | Some("cdn-images-1.medium.com") => {
+            let name = url.path_segments()?.rfind(|s| !s.is_empty())?;
+            Some(V1_API_ROUTES.proxy.get_medium_asset(name))
+        }
+        _ if is_medium(&url) => None,
+        _ => Some(src.to_owned()),
+    }
+}
+
+fn rewrite_srcset(srcset: &str) -> String {
+    srcset
+        .split(',')
+        .filter_map(|candidate| {
+            let mut parts = candidate.split_whitespace();
+            let src = rewrite_asset(parts.next()?)?;
+            Some(
+                std::iter::once(src.as_str())
+                    .chain(parts)
+                    .collect::<Vec<&str>>()
+                    .join(" "),
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Remove event handler attributes(`onload`, `onclick`, etc.) and `javascript:` URLs
+fn strip_handlers(el: &mut Element) {
+    let names: Vec<String> = el
+        .attributes()
+        .iter()
+        .filter(|attr| {
+            attr.name().starts_with("on")
+                || attr
+                    .value()
+                    .trim_start()
+                    .to_lowercase()
+                    .starts_with("javascript:")
+        })
+        .map(|attr| attr.name())
+        .collect();
+    for name in names.iter() {
+        el.remove_attribute(name);
+    }
+}
+
+/// Sanitize a media page fetched from Medium
+pub fn sanitize(html: &str) -> String {
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("script, noscript, link, base, meta, object, embed", |el| {
+                    el.remove();
+                    Ok(())
+                }),
+                element!("*", |el| {
+                    strip_handlers(el);
+                    Ok(())
+                }),
+                element!("img, source, video, audio", |el| {
+                    for attr in ["src", "poster"] {
+                        if let Some(src) = el.get_attribute(attr) {
+                            match rewrite_asset(&src) {
+                                Some(src) => el.set_attribute(attr, &src)?,
+                                None => {
+                                    el.remove();
+                                    return Ok(());
+                                }
+                            }
+                        }
+                    }
+                    if let Some(srcset) = el.get_attribute("srcset") {
+                        el.set_attribute("srcset", &rewrite_srcset(&srcset))?;
+                    }
+                    Ok(())
+                }),
+                element!("iframe[src]", |el| {
+                    // handlers run in order, `src` might've been removed already
+                    let src = el.get_attribute("src").unwrap_or_default();
+                    if let Some(url) = Url::parse(&src).ok().filter(is_medium) {
+                        match media_id(&url) {
+                            Some(id) => {
+                                el.set_attribute("src", &V1_API_ROUTES.proxy.get_media(&id))?
+                            }
+                            // other Medium pages would track readers
+                            None => el.remove(),
+                        }
+                    }
+                    Ok(())
+                }),
+                element!("a[href]", |el| {
+                    if let Some(href) = el.get_attribute("href") {
+                        el.set_attribute("href", &rewrite_link(&href))?;
+                    }
+                    el.set_attribute("target", "_blank")?;
+                    el.set_attribute("rel", "noopener noreferrer")?;
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        },
+    )
+    .unwrap_or_default()
+}
+
+/// Extract media ID from `https://medium.com/media/{id}` URLs
+pub fn media_id(url: &Url) -> Option<String> {
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+    if segments.next()? != "media" {
+        return None;
+    }
+    segments
+        .next()
+        .filter(|id| is_media_id(id))
+        .map(|id| id.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_works() {
+        let html = r#"<html><head><script src="https://cdn.embedly.com/widgets.js"></script>
+<link rel="stylesheet" href="https://glyph.medium.com/css/e.css"><style>body{margin:0}</style></head>
+<body onload="track()"><a href="https://medium.com/p/7158b1cdd50c" onclick="track()">post</a>
+<a href="javascript:alert(1)">bad</a>
+<img src="https://miro.medium.com/v2/resize:fit:700/1*LY2ohYsNa9nOV1Clko3zJA.png" srcset="https://miro.medium.com/max/1400/1*LY2ohYsNa9nOV1Clko3zJA.png 2x">
+<img src="https://medium.com/_/stat?event=embed.loaded">
+<iframe src="https://medium.com/media/0123456789abcdef0123456789abcdef"></iframe>
+<iframe src="https://glyph.medium.com/embed/track"></iframe>
+<iframe src="https://medium.com/@someone/some-post-7158b1cdd50c"></iframe>
+<iframe src="https://www.youtube-nocookie.com/embed/c1gO9aB9nbs"></iframe></body></html>"#;
+        let sanitized = sanitize(html);
+
+        assert!(!sanitized.contains("<script"));
+        assert!(!sanitized.contains("<link"));
+        assert!(sanitized.contains("<style>body{margin:0}</style>"));
+        assert!(!sanitized.contains("onload"));
+        assert!(!sanitized.contains("onclick"));
+        assert!(!sanitized.contains("javascript:"));
+        assert!(!sanitized.contains("_/stat"));
+        assert!(!sanitized.contains("medium.com/"));
+        assert_eq!(sanitized.matches("<iframe").count(), 2);
+        assert!(sanitized.contains(r#"href="/utils/post/7158b1cdd50c""#));
+        assert!(sanitized.contains(r#"src="/asset/medium/1*LY2ohYsNa9nOV1Clko3zJA.png""#));
+        assert!(sanitized.contains(r#"srcset="/asset/medium/1*LY2ohYsNa9nOV1Clko3zJA.png 2x""#));
+        assert!(sanitized.contains(r#"src="/media/0123456789abcdef0123456789abcdef""#));
+        assert!(sanitized.contains(r#"src="https://www.youtube-nocookie.com/embed/c1gO9aB9nbs""#));
+    }
+}
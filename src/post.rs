@@ -16,10 +16,64 @@
  */
 use std::collections::HashMap;
 
+use crate::code_embed::CodeEmbed;
 use crate::data::*;
+use crate::embed::Embed;
 use crate::proxy::StringUtils;
 use get_post::*;
 
+/// What rendering needs from the instance. Rendering doesn't touch the cache itself:
+/// [Data] registers third-party assets and memoises highlighted code.
+pub trait RenderContext {
+    /// Instance URL that serves a third-party asset, see [Data::get_external_asset]
+    fn external_asset(&self, url: &str) -> String;
+
+    /// Highlighted code, see [Data::highlight]
+    fn highlight(
+        &self,
+        key: &str,
+        id: &str,
+        query: &crate::render_html::SourcegraphQuery,
+    ) -> String;
+}
+
+impl RenderContext for Data {
+    fn external_asset(&self, url: &str) -> String {
+        self.get_external_asset(url)
+    }
+
+    fn highlight(
+        &self,
+        key: &str,
+        id: &str,
+        query: &crate::render_html::SourcegraphQuery,
+    ) -> String {
+        Data::highlight(self, key, id, query)
+    }
+}
+
+/// Renders without registering assets or memoising highlighted code
+#[cfg(test)]
+pub struct Uncached;
+
+#[cfg(test)]
+impl RenderContext for Uncached {
+    fn external_asset(&self, url: &str) -> String {
+        crate::V1_API_ROUTES
+            .proxy
+            .get_external_asset(&sha256::digest(url))
+    }
+
+    fn highlight(
+        &self,
+        _key: &str,
+        id: &str,
+        query: &crate::render_html::SourcegraphQuery,
+    ) -> String {
+        query.syntax_highlight(id)
+    }
+}
+
 #[derive(Eq, PartialEq)]
 enum PostitionType {
     Start,
@@ -27,33 +81,50 @@ enum PostitionType {
 }
 
 #[derive(Default, Eq, PartialEq)]
-struct ListState {
+struct RenderState {
     in_uli: bool,
     in_oli: bool,
+    in_image_row: bool,
 }
 
-struct Markup<'a, 'b> {
+struct Markup<'a> {
     markup: &'a GetPostPostContentBodyModelParagraphsMarkups,
-    p: &'a GetPostPostContentBodyModelParagraphs,
     pos_type: PostitionType,
-    gists: &'b Option<Vec<(String, crate::data::GistContent)>>,
 }
 
-impl<'a, 'b> Markup<'a, 'b> {
+impl<'a> Markup<'a> {
     fn start(
         p: &GetPostPostContentBodyModelParagraphs,
-        gists: &'b Option<Vec<(String, crate::data::GistContent)>>,
+        gists: &Option<Vec<(String, Option<crate::data::GistContent>)>>,
+        ctx: &dyn RenderContext,
         pindex: usize,
-        state: &mut ListState,
+        state: &mut RenderState,
     ) -> String {
         let list = Self::list_close(p, state);
+        let row = Self::image_row_close(p, state);
         let resp = if p.type_ == "IMG" {
             let metadata = p.metadata.as_ref().unwrap();
-            format!(
-                r#"<figure><img width="{}" src="{}" /> <figcaption>"#,
-                metadata.original_width.as_ref().unwrap(),
-                crate::V1_API_ROUTES.proxy.get_medium_asset(&metadata.id)
-            )
+            let img = Self::img(metadata);
+            match p.layout.as_deref() {
+                Some("OUTSET_ROW") | Some("OUTSET_ROW_CONTINUE") => {
+                    let ratio = match (metadata.original_width, metadata.original_height) {
+                        (Some(w), Some(h)) if h > 0 => w as f64 / h as f64,
+                        _ => 1.0,
+                    };
+                    let figure =
+                        format!(r#"<figure style="flex: {ratio:.3} 1 0">{img} <figcaption>"#);
+                    if state.in_image_row {
+                        figure
+                    } else {
+                        state.in_image_row = true;
+                        format!(r#"<figure class="image-grid">{figure}"#)
+                    }
+                }
+                layout => match Self::layout_class(layout) {
+                    Some(class) => format!(r#"<figure class="{class}">{img} <figcaption>"#),
+                    None => format!("<figure>{img} <figcaption>"),
+                },
+            }
         } else if p.type_ == "P" {
             "<p>".into()
         } else if p.type_ == "PRE" {
@@ -78,38 +149,27 @@ impl<'a, 'b> Markup<'a, 'b> {
         } else if p.type_ == "H6" {
             "<h6>".into()
         } else if p.type_ == "IFRAME" {
-            let src = &p
-                .iframe
-                .as_ref()
-                .unwrap()
-                .media_resource
-                .as_ref()
-                .unwrap()
-                .href;
-            if src.contains("gist.github.com") {
-                let gist_id = crate::data::Data::get_gist_id(src);
-                let (_, gist) = gists
-                    .as_ref()
-                    .unwrap()
-                    .iter()
-                    .find(|(id, _)| id == gist_id)
+            let media = p.iframe.as_ref().unwrap().media_resource.as_ref().unwrap();
+            let src = &media.href;
+            if let Some(code) = CodeEmbed::classify(src) {
+                let content = gists
                     .as_ref()
-                    .unwrap();
-
-                let mut gists = String::default();
-                for file in &gist.files {
-                    gists += &format!(
-                        r#"<div class="code-block gist-block">{}</div>"#,
-                        file.get_html_content()
-                    );
+                    .and_then(|gists| gists.iter().find(|(url, _)| url == src))
+                    .and_then(|(_, content)| content.as_ref());
+
+                match content {
+                    Some(content) => Self::code_embed(&code, content),
+                    None => Self::code_embed_fallback(&code, src),
                 }
-                format!(
-                    r#"<div class="gist_container">{gists}
-                     <a class="gist_link" href="{}" target="_blank">See gist on GitHub</a>"#,
-                    &gist.html_url
-                )
             } else {
-                format!(r#"<iframe src="{src}" frameborder="0">"#)
+                Embed::new(
+                    src,
+                    Some(media.iframe_src.as_str()),
+                    media.thumbnail_url.as_deref(),
+                    media.title.as_deref(),
+                )
+                .media(&media.id, Some(media.iframe_width), media.iframe_height)
+                .render(ctx)
             }
         } else if p.type_ == "ULI" {
             if state.in_uli {
@@ -145,16 +205,215 @@ impl<'a, 'b> Markup<'a, 'b> {
                 .into()
         };
 
-        match list {
-            Some(list) => format!("{list}{resp}"),
-            None => resp,
+        format!(
+            "{}{}{resp}",
+            list.unwrap_or_default(),
+            row.unwrap_or_default()
+        )
+    }
+
+    /// Link card for `MIXTAPE_EMBED` paragraphs. Older posts don't carry mixtape
+    /// metadata, those are rendered as plain paragraphs.
+    fn mixtape(
+        p: &GetPostPostContentBodyModelParagraphs,
+        state: &mut RenderState,
+    ) -> Option<String> {
+        if p.type_ != "MIXTAPE_EMBED" {
+            return None;
         }
+        let mixtape = p.mixtape_metadata.as_ref()?;
+
+        // Mixtape text is the title in bold, followed by description in italics
+        // and the domain of the link
+        let text_of = |type_: &str| {
+            p.markups.iter().find(|m| m.type_ == type_).map(|m| {
+                p.text
+                    .slice(m.start as usize..m.end as usize)
+                    .trim()
+                    .to_owned()
+            })
+        };
+        let resource = mixtape.media_resource.as_ref();
+        let title = resource
+            .and_then(|r| r.title.clone())
+            .or_else(|| text_of("STRONG"))
+            .unwrap_or_else(|| p.text.clone());
+        let description = resource
+            .and_then(|r| r.description.clone())
+            .or_else(|| text_of("EM"))
+            .unwrap_or_default();
+        let host = url::Url::parse(&mixtape.href)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_owned()))
+            .unwrap_or_default();
+        let thumbnail = match &mixtape.thumbnail_image_id {
+            Some(id) => format!(
+                r#"<img class="mixtape__thumbnail" loading="lazy" src="{}" alt="" />"#,
+                crate::V1_API_ROUTES.proxy.get_medium_asset_sized(id, 320)
+            ),
+            None => String::default(),
+        };
+
+        Some(format!(
+            r#"{}{}<a class="mixtape" rel="noreferrer" href="{}"><div class="mixtape__text"><strong>{}</strong><em>{}</em><span class="mixtape__host">{}</span></div>{thumbnail}</a>"#,
+            Self::list_close(p, state).unwrap_or_default(),
+            Self::image_row_close(p, state).unwrap_or_default(),
+            escape(&crate::resolve::rewrite_link(&mixtape.href)),
+            escape(&title),
+            escape(&description),
+            escape(&host),
+        ))
+    }
+
+    /// Gists and other code embeds. Like embeds, they are closed by [Markup::end].
+    fn code_embed(code: &CodeEmbed, content: &GistContent) -> String {
+        let mut files = String::default();
+        for file in &content.files {
+            files += &format!(
+                r#"<div class="code-block gist-block">{}</div>"#,
+                file.get_html_content()
+            );
+            // only cached gists are served by the instance
+            if let (CodeEmbed::Gist(gist), true) = (code, content.cached) {
+                let raw = crate::V1_API_ROUTES.proxy.get_gist_raw(
+                    &gist.id,
+                    &file.file_name,
+                    gist.revision.as_deref(),
+                );
+                files += &format!(
+                    r#"
+                     <a class="gist__raw" href="{}" target="_blank">{} (raw)</a>"#,
+                    escape(&raw),
+                    escape(&file.file_name),
+                );
+            }
+        }
+        let link = match code {
+            CodeEmbed::Gist(_) => "See gist on GitHub".to_owned(),
+            _ => format!("See on {}", code.host()),
+        };
+        format!(
+            r#"<div class="gist_container">{files}
+                     <a class="gist_link" href="{}" target="_blank">{link}</a>"#,
+            escape(&content.html_url)
+        )
+    }
+
+    /// Shown in place of code embeds that couldn't be fetched
+    fn code_embed_fallback(code: &CodeEmbed, src: &str) -> String {
+        format!(
+            r#"<div class="gist_container gist--failed">
+                     <p class="gist__error">Could not load <code>{}</code> from {}.</p>
+                     <a class="gist_link" href="{}" target="_blank">See on {}</a>"#,
+            escape(&code.file_name()),
+            code.host(),
+            escape(src),
+            code.host(),
+        )
+    }
+
+    /// Syntax highlighted code block. Medium stores each code block as consecutive
+    /// PRE paragraphs, with the language of the block in the first one. The block
+    /// is identified by `{code_id}-{pindex}`. Markups of the paragraphs aren't
+    /// rendered, highlighting takes their place.
+    fn code_block(
+        block: &[GetPostPostContentBodyModelParagraphs],
+        ctx: &dyn RenderContext,
+        code_id: &str,
+        pindex: usize,
+        state: &mut RenderState,
+    ) -> String {
+        let first = &block[0];
+        // the highlighter expects every line, including the last one, to end with a newline
+        let code = block
+            .iter()
+            .fold(String::default(), |code, p| code + &p.text + "\n");
+        let lang = first
+            .code_block_metadata
+            .as_ref()
+            .and_then(|m| m.lang.as_deref());
+        let highlight = crate::render_html::SourcegraphQuery {
+            filepath: "",
+            code: &code,
+            lang,
+        };
+        let id = format!("{code_id}-{pindex}");
+        let key = sha256::digest(format!("{id}\n{}\n{code}", lang.unwrap_or_default()));
+        format!(
+            r#"{}{}<div class="code-block">{}</div>"#,
+            Self::list_close(first, state).unwrap_or_default(),
+            Self::image_row_close(first, state).unwrap_or_default(),
+            ctx.highlight(&key, &id, &highlight)
+        )
+    }
+
+    /// CSS class for Medium's image layouts. `INSET_CENTER` is the default and
+    /// needs no class, rows are handled separately as grids.
+    fn layout_class(layout: Option<&str>) -> Option<&'static str> {
+        match layout? {
+            "OUTSET_CENTER" => Some("layout--outset-center"),
+            "OUTSET_LEFT" => Some("layout--outset-left"),
+            "INSET_LEFT" => Some("layout--inset-left"),
+            "FULL_WIDTH" => Some("layout--full-width"),
+            _ => None,
+        }
+    }
+
+    /// Consecutive `OUTSET_ROW_CONTINUE` images after an `OUTSET_ROW` image belong to
+    /// the same grid, which is closed at the first paragraph that doesn't continue it
+    fn image_row_close(
+        p: &GetPostPostContentBodyModelParagraphs,
+        state: &mut RenderState,
+    ) -> Option<&'static str> {
+        if state.in_image_row
+            && !(p.type_ == "IMG" && p.layout.as_deref() == Some("OUTSET_ROW_CONTINUE"))
+        {
+            state.in_image_row = false;
+            return Some("</figure>");
+        }
+        None
+    }
+
+    /// Responsive image: resized variants are served through the asset proxy so that
+    /// small screens don't have to download originals
+    fn img(metadata: &GetPostPostContentBodyModelParagraphsMetadata) -> String {
+        const DEFAULT_WIDTH: i64 = 1024;
+        const SIZES: &str = "(max-width: 1200px) 90vw, 910px";
+
+        let proxy = &crate::V1_API_ROUTES.proxy;
+        let original = proxy.get_medium_asset(&metadata.id);
+        let width = match metadata.original_width {
+            Some(width) => width,
+            None => return format!(r#"<img loading="lazy" src="{original}" />"#),
+        };
+
+        let mut srcset: Vec<String> = crate::proxy::ASSET_WIDTHS
+            .iter()
+            .filter(|w| **w < width)
+            .map(|w| format!("{} {w}w", proxy.get_medium_asset_sized(&metadata.id, *w)))
+            .collect();
+        srcset.push(format!("{original} {width}w"));
+
+        let src = if width > DEFAULT_WIDTH {
+            proxy.get_medium_asset_sized(&metadata.id, DEFAULT_WIDTH)
+        } else {
+            original
+        };
+        let height = match metadata.original_height {
+            Some(height) => format!(r#" height="{height}""#),
+            None => String::default(),
+        };
+
+        format!(
+            r#"<img width="{width}"{height} loading="lazy" src="{src}" srcset="{}" sizes="{SIZES}" />"#,
+            srcset.join(", ")
+        )
     }
 
     fn end(
         p: &GetPostPostContentBodyModelParagraphs,
         pindex: usize,
-        state: &mut ListState,
+        state: &mut RenderState,
     ) -> String {
         let resp: String = if p.type_ == "IMG" {
             "</figcaption></figure>".into()
@@ -182,19 +441,8 @@ impl<'a, 'b> Markup<'a, 'b> {
         } else if p.type_ == "H6" {
             "</h6>".into()
         } else if p.type_ == "IFRAME" {
-            let src = &p
-                .iframe
-                .as_ref()
-                .unwrap()
-                .media_resource
-                .as_ref()
-                .unwrap()
-                .href;
-            if src.contains("gist.github.com") {
-                "</div>".into()
-            } else {
-                "</iframe>".into()
-            }
+            // gists and embeds are both wrapped in a div
+            "</div>".into()
         } else if p.type_ == "OLI" || p.type_ == "ULI" {
             "</li>".into()
         } else if p.type_ == "MIXTAPE_EMBED" {
@@ -223,7 +471,7 @@ impl<'a, 'b> Markup<'a, 'b> {
 
     fn list_close(
         p: &GetPostPostContentBodyModelParagraphs,
-        state: &mut ListState,
+        state: &mut RenderState,
     ) -> Option<String> {
         if state.in_oli && p.type_ != "OLI" {
             state.in_oli = false;
@@ -243,7 +491,7 @@ impl<'a, 'b> Markup<'a, 'b> {
                     if self.pos_type == PostitionType::Start {
                         format!(
                             r#"<a rel="noreferrer" href="{}">"#,
-                            self.markup.href.as_ref().unwrap()
+                            crate::resolve::rewrite_link(self.markup.href.as_ref().unwrap())
                         )
                     } else {
                         "</a>".into()
@@ -251,8 +499,10 @@ impl<'a, 'b> Markup<'a, 'b> {
                 } else if anchor_type == "USER" {
                     if self.pos_type == PostitionType::Start {
                         format!(
-                            r#"<a rel="noreferrer" href="https://medium.com/u/{}">"#,
-                            self.markup.user_id.as_ref().unwrap()
+                            r#"<a href="{}">"#,
+                            crate::V1_API_ROUTES
+                                .proxy
+                                .get_by_user_id(self.markup.user_id.as_ref().unwrap())
                         )
                     } else {
                         "</a>".into()
@@ -322,14 +572,21 @@ impl<'a, 'b> Markup<'a, 'b> {
     }
 }
 
+/// Escape text for HTML content and attribute values
+pub fn escape(s: &str) -> String {
+    let mut buf = String::with_capacity(s.len());
+    sailfish::runtime::escape::escape_to_string(s, &mut buf);
+    buf
+}
+
 #[derive(Default)]
-struct PositionMap<'a, 'b> {
-    map: HashMap<i64, Vec<Markup<'a, 'b>>>,
+struct PositionMap<'a> {
+    map: HashMap<i64, Vec<Markup<'a>>>,
     arr: Vec<i64>,
 }
 
-impl<'a, 'b> PositionMap<'a, 'b> {
-    fn insert_if_not_exists(&mut self, pos: i64, m: Markup<'a, 'b>) {
+impl<'a> PositionMap<'a> {
+    fn insert_if_not_exists(&mut self, pos: i64, m: Markup<'a>) {
         if let Some(markups) = self.map.get_mut(&pos) {
             markups.push(m);
         } else {
@@ -340,30 +597,75 @@ impl<'a, 'b> PositionMap<'a, 'b> {
 }
 
 pub fn apply_markup(
-    data: &PostResp,
-    gists: &Option<Vec<(String, crate::data::GistContent)>>,
+    data: &[Paragraph],
+    gists: &Option<Vec<(String, Option<crate::data::GistContent>)>>,
+    ctx: &dyn RenderContext,
+) -> Vec<String> {
+    render_paragraphs(data, gists, ctx, "code")
+}
+
+/// Render a response. Responses are shown on the same page, so IDs of their code
+/// blocks are prefixed with the ID of the response to keep them unique.
+pub fn apply_response_markup(
+    response_id: &str,
+    data: &[Paragraph],
+    ctx: &dyn RenderContext,
+) -> Vec<String> {
+    let response_id: String = response_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    render_paragraphs(data, &None, ctx, &format!("code-{response_id}"))
+}
+
+/// `code_id` is the prefix of IDs of code blocks, see [Markup::code_block]
+fn render_paragraphs(
+    data: &[Paragraph],
+    gists: &Option<Vec<(String, Option<crate::data::GistContent>)>>,
+    ctx: &dyn RenderContext,
+    code_id: &str,
 ) -> Vec<String> {
-    let mut paragraphs: Vec<String> = Vec::with_capacity(data.content.body_model.paragraphs.len());
-    let mut state = ListState::default();
-    let mut no_render_html = false;
-    for (pindex, p) in data.content.body_model.paragraphs.iter().enumerate() {
+    let mut paragraphs: Vec<String> = Vec::with_capacity(data.len());
+    let mut state = RenderState::default();
+    // consecutive PRE paragraphs are merged into one code block
+    let mut code_block_end = 0;
+    for (pindex, p) in data.iter().enumerate() {
         let mut pos = PositionMap::default();
         if p.type_ == "H3" && pindex == 0 {
             log::debug!("FOUND TOP LEVEL H3. Breaking");
             continue;
         }
+        if pindex < code_block_end {
+            continue;
+        }
+        if let Some(card) = Markup::mixtape(p, &mut state) {
+            paragraphs.push(card);
+            continue;
+        }
+        if p.type_ == "PRE" {
+            code_block_end = pindex
+                + data[pindex..]
+                    .iter()
+                    .take_while(|p| p.type_ == "PRE")
+                    .count();
+            paragraphs.push(Markup::code_block(
+                &data[pindex..code_block_end],
+                ctx,
+                code_id,
+                pindex,
+                &mut state,
+            ));
+            continue;
+        }
         for m in p.markups.iter() {
             let start_markup = Markup {
                 markup: m,
-                p,
-                gists,
                 pos_type: PostitionType::Start,
             };
             pos.insert_if_not_exists(m.start, start_markup);
             let end_markup = Markup {
                 markup: m,
-                p,
-                gists,
                 pos_type: PostitionType::End,
             };
 
@@ -384,11 +686,8 @@ pub fn apply_markup(
         }
 
         let mut content = String::with_capacity(p.text.len());
-        let start = &Markup::start(p, gists, pindex, &mut state);
+        let start = &Markup::start(p, gists, ctx, pindex, &mut state);
         content += start;
-        if start == "<pre>" {
-            no_render_html = true;
-        }
         pos.arr.sort();
         let mut page = String::default();
         if let Some(first) = pos.arr.first() {
@@ -407,22 +706,229 @@ pub fn apply_markup(
             }
             log::debug!("LAST");
             page += p.text.slice(cur..);
-            let end = &Markup::end(p, pindex, &mut state);
-            if end == "</pre>" {
-                no_render_html = false;
-            }
             content += &page;
-            content += end;
+            content += &Markup::end(p, pindex, &mut state);
         } else {
             log::debug!("LAST WITH NO MARKUP");
             page += p.text.slice(cur..);
-            if no_render_html {
-                page = page.replace("<", "&lt;").replace(">", "&gt;");
-            }
             content += &page;
             content += &Markup::end(p, pindex, &mut state);
         }
         paragraphs.push(content);
     }
+    if state.in_image_row {
+        paragraphs.push("</figure>".into());
+    }
     paragraphs
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_work() {
+        let post = crate::proxy::tests::post();
+        let paragraphs = apply_markup(&post.content.body_model.paragraphs, &None, &Uncached);
+
+        assert!(paragraphs
+            .iter()
+            .any(|p| p.starts_with(r#"<figure class="layout--outset-center">"#)));
+
+        let grid = paragraphs
+            .iter()
+            .position(|p| p.starts_with(r#"<figure class="image-grid">"#))
+            .unwrap();
+        assert!(!paragraphs[grid + 1].starts_with(r#"<figure class="image-grid">"#));
+        assert!(paragraphs[grid + 1].starts_with("<figure style="));
+        assert!(paragraphs[grid + 2].starts_with("</figure><h3>"));
+    }
+
+    #[test]
+    fn mixtape_works() {
+        let post = crate::proxy::tests::post();
+        let paragraphs = apply_markup(&post.content.body_model.paragraphs, &None, &Uncached);
+        let card = paragraphs
+            .iter()
+            .find(|p| p.contains(r#"class="mixtape""#))
+            .unwrap();
+        assert!(card.starts_with(
+            r#"</ol><a class="mixtape" rel="noreferrer" href="/utils/post/b62607a43a8c">"#
+        ));
+        assert!(card.contains("<strong>Big Data, Small Effort</strong>"));
+        assert!(card.contains("<em>Less is more</em>"));
+        assert!(card.contains(r#"src="/asset/medium/1*LY2ohYsNa9nOV1Clko3zJA.png?w=320""#));
+    }
+
+    #[test]
+    fn embeds_work() {
+        let post = crate::proxy::tests::post();
+        let paragraphs = apply_markup(&post.content.body_model.paragraphs, &None, &Uncached);
+        let embeds: Vec<&String> = paragraphs
+            .iter()
+            .filter(|p| p.contains(r#"class="embed "#))
+            .collect();
+        assert_eq!(embeds.len(), 3);
+
+        let video = embeds[0];
+        assert!(video.starts_with(r#"<div class="embed embed--youtube"><iframe srcdoc=""#));
+        assert!(!video.contains("<iframe src="));
+        assert!(video.contains("youtube-nocookie.com/embed/c1gO9aB9nbs"));
+        assert!(!video.contains("i.ytimg.com"));
+        assert!(video.ends_with("</div>"));
+
+        let tweet = embeds[1];
+        assert!(tweet.starts_with(r#"<div class="embed embed--twitter"><blockquote>"#));
+        assert!(!tweet.contains("<iframe"));
+
+        let media = embeds[2];
+        assert!(media.contains("/media/0123456789abcdef0123456789abcdef"));
+        assert!(media.contains("aspect-ratio: 640 / 480"));
+        assert!(!media.contains("asciinema.org/a/113463&quot;"));
+    }
+
+    #[test]
+    fn gist_fallback_works() {
+        let mut post = crate::proxy::tests::post();
+        let iframe = post
+            .content
+            .body_model
+            .paragraphs
+            .iter_mut()
+            .find(|p| p.type_ == "IFRAME")
+            .unwrap();
+        const GIST: &str =
+            "https://gist.github.com/realaravinth/f2ed2b0c7d5e2b1e4c4d3a0e5b2f7a1c?file=main.rs";
+        iframe
+            .iframe
+            .as_mut()
+            .unwrap()
+            .media_resource
+            .as_mut()
+            .unwrap()
+            .href = GIST.into();
+
+        for gists in [None, Some(vec![(GIST.to_owned(), None)])] {
+            let paragraphs = apply_markup(&post.content.body_model.paragraphs, &gists, &Uncached);
+            let fallback = paragraphs
+                .iter()
+                .find(|p| p.contains("gist--failed"))
+                .unwrap();
+            assert!(fallback.contains("Could not load <code>main.rs</code> from GitHub."));
+            assert!(fallback.contains(&format!(r#"href="{GIST}""#)));
+            assert!(fallback.ends_with("</div>"));
+        }
+
+        for cached in [true, false] {
+            let content = GistContent {
+                files: vec![GistFile {
+                    file_name: "main.rs".into(),
+                    content: "<pre>highlighted</pre>".into(),
+                    language: "Rust".into(),
+                    raw_url: String::default(),
+                }],
+                html_url: GIST.into(),
+                cached,
+            };
+            let gists = Some(vec![(GIST.to_owned(), Some(content))]);
+            let paragraphs = apply_markup(&post.content.body_model.paragraphs, &gists, &Uncached);
+            let gist = paragraphs
+                .iter()
+                .find(|p| p.contains("<pre>highlighted</pre>"))
+                .unwrap();
+            // gists fetched without the API aren't cached, so they can't be served
+            assert_eq!(
+                gist.contains(r#"href="/gist/f2ed2b0c7d5e2b1e4c4d3a0e5b2f7a1c/main.rs/raw""#),
+                cached
+            );
+        }
+    }
+
+    #[test]
+    fn code_embeds_work() {
+        let mut post = crate::proxy::tests::post();
+        const FILE: &str =
+            "https://github.com/realaravinth/libmedium/blob/8e1ab0c/src/data.rs#L1-L2";
+        const PASTE: &str = "https://pastebin.com/UIFdu235";
+        let mut iframes = post
+            .content
+            .body_model
+            .paragraphs
+            .iter_mut()
+            .filter(|p| p.type_ == "IFRAME");
+        for src in [FILE, PASTE] {
+            iframes
+                .next()
+                .unwrap()
+                .iframe
+                .as_mut()
+                .unwrap()
+                .media_resource
+                .as_mut()
+                .unwrap()
+                .href = src.into();
+        }
+
+        let content = GistContent {
+            files: vec![GistFile {
+                file_name: "data.rs".into(),
+                content: "<pre>highlighted</pre>".into(),
+                language: String::default(),
+                raw_url: "https://raw.githubusercontent.com/realaravinth/libmedium/8e1ab0c/src/data.rs#L1-L2".into(),
+            }],
+            html_url: FILE.into(),
+            cached: false,
+        };
+        let gists = Some(vec![
+            (FILE.to_owned(), Some(content)),
+            (PASTE.to_owned(), None),
+        ]);
+        let paragraphs = apply_markup(&post.content.body_model.paragraphs, &gists, &Uncached);
+
+        let file = paragraphs
+            .iter()
+            .find(|p| p.contains("<pre>highlighted</pre>"))
+            .unwrap();
+        assert!(file.contains(r#"<a class="gist_link" href="https://github.com/realaravinth/libmedium/blob/8e1ab0c/src/data.rs#L1-L2" target="_blank">See on GitHub</a>"#));
+        // only gists are served by the instance
+        assert!(!file.contains("(raw)"));
+
+        let paste = paragraphs
+            .iter()
+            .find(|p| p.contains("gist--failed"))
+            .unwrap();
+        assert!(paste.contains("Could not load <code>UIFdu235</code> from Pastebin."));
+    }
+
+    #[test]
+    fn code_blocks_work() {
+        let post = crate::proxy::tests::post();
+        let paragraphs = apply_markup(&post.content.body_model.paragraphs, &None, &Uncached);
+        let blocks: Vec<&String> = paragraphs
+            .iter()
+            .filter(|p| p.starts_with(r#"<div class="code-block">"#))
+            .collect();
+        assert_eq!(blocks.len(), 1);
+        let block = blocks[0];
+        assert!(!paragraphs.iter().any(|p| p.starts_with("<pre>")));
+        assert!(block.contains(r#"<pre class="syn-code" id="code-13">"#));
+        assert!(block.contains(r#"<span class="line" id="code-13-L1">"#));
+        assert!(block.contains(r##"href="?hl=code-13-L3#code-13-L3">3</a>"##));
+        // highlighted as rust, so `fn` is styled
+        assert!(!block.contains(">fn cas("));
+        assert!(block.contains("compare_and_swap"));
+
+        // code blocks of responses on the same page don't share IDs
+        let paragraphs = apply_response_markup(
+            "A1b2c3d4e5f6\"",
+            &post.content.body_model.paragraphs,
+            &Uncached,
+        );
+        let block = paragraphs
+            .iter()
+            .find(|p| p.starts_with(r#"<div class="code-block">"#))
+            .unwrap();
+        assert!(block.contains(r#"<pre class="syn-code" id="code-a1b2c3d4e5f6-13">"#));
+        assert!(block.contains(r#"<span class="line" id="code-a1b2c3d4e5f6-13-L1">"#));
+    }
+}
@@ -14,27 +14,57 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use std::cmp::Reverse;
 use std::ops::{Bound, RangeBounds};
 
-use actix_web::{http::header, web, HttpResponse, Responder};
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie, SameSite};
+use actix_web::{http::header, web, HttpMessage, HttpRequest, HttpResponse, Responder};
 use chrono::{TimeZone, Utc};
 use futures::future::join_all;
 use reqwest::header::CONTENT_TYPE;
 use sailfish::TemplateOnce;
+use serde::Deserialize;
 
-use crate::data::PostResp;
-use crate::post::apply_markup;
+use crate::code_embed::CodeEmbed;
+use crate::data::{get_post, Data, FetchError, GistRef, PostResp, PostResponse, UserResp};
+use crate::post::{apply_markup, apply_response_markup, RenderContext};
+use crate::render_html::{line_highlight_css, ThemeMode, THEME_CSS};
+use crate::resolve::get_post_id;
 use crate::AppData;
 
 const CACHE_AGE: u32 = 60 * 60 * 24;
 
+/// Widths that the asset route will ask miro to resize images to. Arbitrary widths
+/// aren't allowed so that the proxy can't be used to generate unlimited variants.
+pub const ASSET_WIDTHS: [i64; 5] = [320, 640, 1024, 1400, 2000];
+
+/// URL of a Medium image, resized to `width` when it is set
+pub fn miro_url(name: &str, width: Option<i64>) -> String {
+    match width {
+        None => format!("https://miro.medium.com/{name}"),
+        Some(w) => format!("https://miro.medium.com/v2/resize:fit:{w}/{name}"),
+    }
+}
+
 pub mod routes {
     pub struct Proxy {
         pub index: &'static str,
         pub by_post_id: &'static str,
         pub page: &'static str,
+        pub markdown: &'static str,
+        pub epub: &'static str,
+        pub epub_batch: &'static str,
+        pub responses: &'static str,
         pub asset: &'static str,
+        pub external_asset: &'static str,
+        pub media: &'static str,
+        pub syntax_css: &'static str,
+        pub syntax_theme: &'static str,
+        pub gist_raw: &'static str,
         pub top_level_post: &'static str,
+        pub resolve: &'static str,
+        pub author: &'static str,
+        pub by_user_id: &'static str,
     }
 
     impl Proxy {
@@ -43,19 +73,91 @@ pub mod routes {
                 index: "/",
                 by_post_id: "/utils/post/{post}",
                 page: "/{username}/{post}",
+                markdown: "/{username}/{post}.md",
+                epub: "/{username}/{post}.epub",
+                epub_batch: "/utils/epub",
+                responses: "/{username}/{post}/responses",
                 asset: "/asset/medium/{name}",
+                external_asset: "/asset/external/{digest}",
+                media: "/media/{id}",
+                syntax_css: "/syntax.css",
+                syntax_theme: "/syntax/theme/{mode}",
+                gist_raw: "/gist/{id}/{file}/raw",
                 top_level_post: "/{post}",
+                resolve: "/resolve",
+                author: "/@{username}",
+                by_user_id: "/utils/user/{user}",
             }
         }
+
+        pub fn get_by_post_id(&self, post_id: &str) -> String {
+            self.by_post_id.replace("{post}", post_id)
+        }
+
+        pub fn get_author(&self, username: &str) -> String {
+            self.author.replace("{username}", username)
+        }
+
+        pub fn get_by_user_id(&self, user_id: &str) -> String {
+            self.by_user_id.replace("{user}", user_id)
+        }
+
         pub fn get_page(&self, username: &str, post: &str) -> String {
             self.page
                 .replace("{username}", username)
                 .replace("{post}", post)
         }
 
+        pub fn get_markdown(&self, username: &str, post: &str) -> String {
+            self.markdown
+                .replace("{username}", username)
+                .replace("{post}", post)
+        }
+
+        pub fn get_epub(&self, username: &str, post: &str) -> String {
+            self.epub
+                .replace("{username}", username)
+                .replace("{post}", post)
+        }
+
+        pub fn get_responses(&self, username: &str, post: &str) -> String {
+            self.responses
+                .replace("{username}", username)
+                .replace("{post}", post)
+        }
+
         pub fn get_medium_asset(&self, asset_name: &str) -> String {
             self.asset.replace("{name}", asset_name)
         }
+
+        pub fn get_external_asset(&self, digest: &str) -> String {
+            self.external_asset.replace("{digest}", digest)
+        }
+
+        pub fn get_media(&self, id: &str) -> String {
+            self.media.replace("{id}", id)
+        }
+
+        pub fn get_syntax_theme(&self, mode: &str) -> String {
+            self.syntax_theme.replace("{mode}", mode)
+        }
+
+        /// Raw contents of a gist file. The file name is percent-encoded.
+        pub fn get_gist_raw(&self, id: &str, file: &str, revision: Option<&str>) -> String {
+            let file = url::form_urlencoded::byte_serialize(file.as_bytes())
+                .collect::<String>()
+                .replace('+', "%20");
+            let path = self.gist_raw.replace("{id}", id).replace("{file}", &file);
+            match revision {
+                Some(revision) => format!("{path}?revision={revision}"),
+                None => path,
+            }
+        }
+
+        /// Resized variant of an asset, `width` must be one of [super::ASSET_WIDTHS]
+        pub fn get_medium_asset_sized(&self, asset_name: &str, width: i64) -> String {
+            format!("{}?w={width}", self.get_medium_asset(asset_name))
+        }
     }
 }
 
@@ -122,9 +224,107 @@ pub struct Post {
     pub date: String,
     pub preview_img: String,
     pub reading_time: usize,
-    pub id: String,
-    pub gists: Option<Vec<(String, crate::data::GistContent)>>,
     pub paragraphs: Vec<String>,
+    /// CSS for lines highlighted with the `hl` query parameter
+    pub line_highlights: String,
+}
+
+impl Post {
+    pub fn new(
+        data: PostResp,
+        gists: &Option<Vec<(String, Option<crate::data::GistContent>)>>,
+        ctx: &dyn RenderContext,
+    ) -> Self {
+        let date = format_date(data.created_at);
+        let reading_time = data.reading_time.floor() as usize;
+        let preview_img = data.preview_image.as_ref().unwrap().id.as_ref().unwrap();
+        let preview_img = crate::V1_API_ROUTES.proxy.get_medium_asset(preview_img);
+
+        let paragraphs = apply_markup(&data.content.body_model.paragraphs, gists, ctx);
+
+        Self {
+            data,
+            date,
+            reading_time,
+            preview_img,
+            paragraphs,
+            line_highlights: String::default(),
+        }
+    }
+}
+
+pub struct RenderedResponse {
+    pub creator: get_post::GetPostPostCreator,
+    pub date: String,
+    pub paragraphs: Vec<String>,
+    pub replies: Vec<RenderedResponse>,
+}
+
+impl RenderedResponse {
+    pub fn new(r: &PostResponse, ctx: &dyn RenderContext) -> Self {
+        Self {
+            creator: r.creator.clone(),
+            date: format_date(r.created_at),
+            paragraphs: apply_response_markup(&r.id, &r.paragraphs, ctx),
+            replies: r.replies.iter().map(|r| Self::new(r, ctx)).collect(),
+        }
+    }
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "author.html")]
+#[template(rm_whitespace = true)]
+pub struct Author {
+    pub user: UserResp,
+    pub posts: Vec<AuthorPost>,
+}
+
+pub struct AuthorPost {
+    pub title: String,
+    pub subtitle: String,
+    pub date: String,
+    pub reading_time: usize,
+    pub url: String,
+}
+
+/// Posts listed on an author's page
+pub fn author_posts(user: &UserResp) -> Vec<AuthorPost> {
+    match &user.homepage_posts_connection {
+        Some(connection) => connection
+            .posts
+            .iter()
+            .map(|post| AuthorPost {
+                title: post.title.clone(),
+                subtitle: post
+                    .preview_content
+                    .as_ref()
+                    .map(|c| c.subtitle.clone())
+                    .unwrap_or_default(),
+                date: format_date(post.created_at),
+                reading_time: post.reading_time.floor() as usize,
+                url: crate::V1_API_ROUTES
+                    .proxy
+                    .get_page(&user.username, &post.unique_slug),
+            })
+            .collect(),
+        None => Vec::default(),
+    }
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "responses.html")]
+#[template(rm_whitespace = true)]
+pub struct Responses {
+    pub data: PostResp,
+    pub responses: Vec<RenderedResponse>,
+}
+
+/// Date shown on posts, empty when Medium sends a timestamp that is out of range
+pub fn format_date(millis: i64) -> String {
+    match Utc.timestamp_millis_opt(millis).single() {
+        Some(date) => date.format("%b %e, %Y").to_string(),
+        None => String::default(),
+    }
 }
 
 const INDEX: &str = include_str!("../templates/index.html");
@@ -136,16 +336,39 @@ async fn index() -> impl Responder {
         .body(INDEX.replace("SOURCE_CODE_REPLACE", &crate::SETTINGS.source_code))
 }
 
+#[derive(Deserialize)]
+struct AssetQuery {
+    w: Option<i64>,
+}
+
 #[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.asset")]
-async fn assets(path: web::Path<String>, data: AppData) -> impl Responder {
-    let res = data
-        .client
-        .get(format!("https://miro.medium.com/{}", path))
-        .send()
-        .await
-        .unwrap();
-    let headers = res.headers();
-    let content_type = headers.get(CONTENT_TYPE).unwrap();
+async fn assets(
+    path: web::Path<String>,
+    query: web::Query<AssetQuery>,
+    data: AppData,
+) -> impl Responder {
+    let url = match query.w {
+        Some(w) if !ASSET_WIDTHS.contains(&w) => {
+            return HttpResponse::BadRequest().body("Unsupported image width")
+        }
+        w => miro_url(&path, w),
+    };
+
+    let res = match data.client.get(url).send().await {
+        Ok(res) if res.status() == reqwest::StatusCode::NOT_FOUND => {
+            return HttpResponse::NotFound().body("Asset not found")
+        }
+        Ok(res) if res.status().is_success() => res,
+        _ => return HttpResponse::BadGateway().finish(),
+    };
+    let content_type = match res.headers().get(CONTENT_TYPE) {
+        Some(content_type) => content_type.clone(),
+        None => return HttpResponse::BadGateway().finish(),
+    };
+    let body = match res.bytes().await {
+        Ok(body) => body,
+        Err(_) => return HttpResponse::BadGateway().finish(),
+    };
     HttpResponse::Ok()
         .insert_header(header::CacheControl(vec![
             header::CacheDirective::Public,
@@ -153,12 +376,170 @@ async fn assets(path: web::Path<String>, data: AppData) -> impl Responder {
             header::CacheDirective::MaxAge(CACHE_AGE),
         ]))
         .content_type(content_type)
-        .body(res.bytes().await.unwrap())
+        .body(body)
 }
 
-#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.by_post_id")]
-async fn by_post_id(path: web::Path<String>, data: AppData) -> impl Responder {
-    let post_data = data.get_post_light(&path).await;
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.external_asset")]
+async fn external_assets(path: web::Path<String>, data: AppData) -> impl Responder {
+    let url = match data.external_assets.get(path.as_str()) {
+        Ok(Some(url)) => String::from_utf8(url.to_vec()).unwrap(),
+        _ => return HttpResponse::NotFound().body("Unknown asset"),
+    };
+
+    let res = match data.client.get(url).send().await {
+        Ok(res) if res.status() == reqwest::StatusCode::NOT_FOUND => {
+            return HttpResponse::NotFound().body("Asset not found")
+        }
+        Ok(res) if res.status().is_success() => res,
+        _ => return HttpResponse::BadGateway().finish(),
+    };
+    let content_type = match res.headers().get(CONTENT_TYPE) {
+        Some(content_type) if content_type.as_bytes().starts_with(b"image/") => {
+            content_type.clone()
+        }
+        _ => return HttpResponse::BadGateway().body("Not an image"),
+    };
+    let body = match res.bytes().await {
+        Ok(body) => body,
+        Err(_) => return HttpResponse::BadGateway().finish(),
+    };
+    HttpResponse::Ok()
+        .insert_header(header::CacheControl(vec![
+            header::CacheDirective::Public,
+            header::CacheDirective::Extension("immutable".into(), None),
+            header::CacheDirective::MaxAge(CACHE_AGE),
+        ]))
+        .content_type(content_type)
+        .body(body)
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.media")]
+async fn media(path: web::Path<String>, data: AppData) -> impl Responder {
+    if !crate::media::is_media_id(&path) {
+        return HttpResponse::BadRequest().body("Invalid media ID");
+    }
+    match data.get_media(&path).await {
+        Some(html) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_SECURITY_POLICY, crate::media::CSP))
+            .insert_header((header::REFERRER_POLICY, "no-referrer"))
+            .insert_header(header::CacheControl(vec![
+                header::CacheDirective::Public,
+                header::CacheDirective::MaxAge(CACHE_AGE),
+            ]))
+            .content_type("text/html; charset=utf-8")
+            .body(html),
+        None => HttpResponse::NotFound().body("Media not found"),
+    }
+}
+
+/// Cookie that stores the syntax highlighting theme that a reader picked
+const THEME_COOKIE: &str = "syntax_theme";
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.syntax_css")]
+async fn syntax_css(req: HttpRequest) -> impl Responder {
+    let mode = req
+        .cookie(THEME_COOKIE)
+        .and_then(|c| ThemeMode::from_name(c.value()))
+        .unwrap_or_default();
+    HttpResponse::Ok()
+        .insert_header(header::CacheControl(vec![
+            header::CacheDirective::Private,
+            header::CacheDirective::MaxAge(CACHE_AGE),
+        ]))
+        .insert_header((header::VARY, "Cookie"))
+        .content_type("text/css; charset=utf-8")
+        .body(THEME_CSS.get(mode))
+}
+
+/// Save reader's theme preference and send them back to where they came from
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.syntax_theme")]
+async fn syntax_theme(path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    if ThemeMode::from_name(&path).is_none() {
+        return HttpResponse::BadRequest().body("Unknown theme");
+    }
+    // only the path is used so that this can't be used as an open redirect. Browsers
+    // treat paths starting with `//` and `/\` as network-path references.
+    let back = req
+        .headers()
+        .get(header::REFERER)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| url::Url::parse(r).ok())
+        .filter(|r| !r.path().starts_with("//") && !r.path().starts_with("/\\"))
+        .map(|r| match r.query() {
+            Some(query) => format!("{}?{query}", r.path()),
+            None => r.path().to_owned(),
+        })
+        .unwrap_or_else(|| crate::V1_API_ROUTES.proxy.index.to_owned());
+
+    let cookie = Cookie::build(THEME_COOKIE, path.into_inner())
+        .path("/")
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::days(365))
+        .finish();
+    HttpResponse::Found()
+        .cookie(cookie)
+        .append_header((header::LOCATION, back))
+        .finish()
+}
+
+#[derive(Deserialize)]
+struct GistRawQuery {
+    revision: Option<String>,
+}
+
+/// Raw contents of cached gist files
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.gist_raw")]
+async fn gist_raw(
+    path: web::Path<(String, String)>,
+    query: web::Query<GistRawQuery>,
+    data: AppData,
+) -> impl Responder {
+    let (id, file_name) = path.into_inner();
+    let gist = GistRef {
+        user: None,
+        id,
+        revision: query.into_inner().revision,
+        file: None,
+    };
+    let file = data
+        .get_cached_gist(&gist.key())
+        .and_then(|gist| gist.files.into_iter().find(|f| f.file_name == file_name));
+    let file = match file {
+        Some(file) => file,
+        None => return HttpResponse::NotFound().body("Gist file not found"),
+    };
+
+    let mut cache = vec![
+        header::CacheDirective::Public,
+        header::CacheDirective::MaxAge(CACHE_AGE),
+    ];
+    if gist.revision.is_some() {
+        cache.push(header::CacheDirective::Extension("immutable".into(), None));
+    }
+    HttpResponse::Ok()
+        .insert_header(header::CacheControl(cache))
+        .insert_header(header::ContentDisposition {
+            disposition: header::DispositionType::Inline,
+            parameters: vec![header::DispositionParam::Filename(file.file_name)],
+        })
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .content_type(crate::gist::content_type(&file_name))
+        .body(file.content)
+}
+
+/// Response for posts that couldn't be fetched
+pub fn post_error(e: FetchError) -> HttpResponse {
+    match e {
+        FetchError::NotFound => HttpResponse::NotFound().body("Post not found"),
+        FetchError::Upstream => HttpResponse::BadGateway().body("Couldn't fetch post from Medium"),
+    }
+}
+
+async fn redirect_to_post(post_id: &str, data: &AppData) -> HttpResponse {
+    let post_data = match data.get_post_light(post_id).await {
+        Ok(post_data) => post_data,
+        Err(e) => return post_error(e),
+    };
     HttpResponse::Found()
         .append_header((
             header::LOCATION,
@@ -169,51 +550,246 @@ async fn by_post_id(path: web::Path<String>, data: AppData) -> impl Responder {
         .finish()
 }
 
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.by_post_id")]
+async fn by_post_id(path: web::Path<String>, data: AppData) -> impl Responder {
+    match get_post_id(&path) {
+        Some(post_id) => redirect_to_post(&post_id, &data).await,
+        None => HttpResponse::BadRequest().body("Invalid post ID"),
+    }
+}
+
 #[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.top_level_post")]
 async fn by_top_level_post(path: web::Path<String>, data: AppData) -> impl Responder {
-    if let Some(post_id) = path.split('-').last() {
-        let post_data = data.get_post_light(post_id).await;
-        HttpResponse::Found()
+    match get_post_id(&path) {
+        Some(post_id) => redirect_to_post(&post_id, &data).await,
+        None => HttpResponse::NotFound().body("Post not found, please file bug report"),
+    }
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.by_user_id")]
+async fn by_user_id(path: web::Path<String>, data: AppData) -> impl Responder {
+    match data.get_user_light(&path).await {
+        Some(username) => HttpResponse::Found()
             .append_header((
                 header::LOCATION,
-                crate::V1_API_ROUTES
-                    .proxy
-                    .get_page(&post_data.username, &post_data.slug),
+                crate::V1_API_ROUTES.proxy.get_author(&username),
             ))
-            .finish()
-    } else {
-        HttpResponse::NotFound().body("Post not found, please file bug report")
+            .finish(),
+        None => HttpResponse::NotFound().body("User not found"),
     }
 }
 
-#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.page")]
-async fn page(path: web::Path<(String, String)>, data: AppData) -> impl Responder {
-    let post_id = path.1.split('-').last();
-    if post_id.is_none() {
-        return HttpResponse::BadRequest().finish();
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.author")]
+async fn author(path: web::Path<String>, data: AppData) -> impl Responder {
+    let user = match data.get_user(&path).await {
+        Some(user) => user,
+        None => return HttpResponse::NotFound().body("User not found"),
+    };
+
+    let posts = author_posts(&user);
+
+    let html = Author { user, posts }.render_once().unwrap();
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html)
+}
+
+#[derive(Deserialize)]
+struct ResolveQuery {
+    url: String,
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.resolve")]
+async fn resolve(query: web::Query<ResolveQuery>, data: AppData) -> impl Responder {
+    let post_id = match get_post_id(&query.url) {
+        Some(post_id) => Some(post_id),
+        None => data.resolve_post_id(&query.url).await,
+    };
+
+    match post_id {
+        Some(post_id) => redirect_to_post(&post_id, &data).await,
+        None => HttpResponse::NotFound().body("Couldn't find a Medium post at this URL"),
     }
-    let id = post_id.unwrap();
+}
+
+/// Representations of posts that the page route can return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Markdown,
+    Text,
+    Json,
+    Epub,
+}
+
+impl Format {
+    /// Parse the `format` query parameter
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "html" => Some(Self::Html),
+            "md" => Some(Self::Markdown),
+            "txt" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            "epub" => Some(Self::Epub),
+            _ => None,
+        }
+    }
+
+    /// Most preferred format of an `Accept` header, `None` when none of the formats
+    /// are acceptable. HTML is preferred when formats are equally acceptable, like
+    /// with wildcards.
+    pub fn from_accept(accept: &header::Accept) -> Option<Self> {
+        const FORMATS: [(Format, &[&str]); 5] = [
+            (Format::Html, &["text/html", "application/xhtml+xml"]),
+            (Format::Markdown, &["text/markdown"]),
+            (Format::Text, &["text/plain"]),
+            (Format::Json, &["application/json"]),
+            (Format::Epub, &["application/epub+zip"]),
+        ];
+        if accept.is_empty() {
+            return Some(Self::Html);
+        }
+        // a type takes the quality of the most specific range that matches it, so
+        // `text/*, text/html;q=0` accepts every text type but HTML. Ties are broken
+        // by the order of the ranges.
+        let quality = |mime: &str| {
+            let type_ = mime.split('/').next().unwrap();
+            accept
+                .iter()
+                .enumerate()
+                .filter_map(|(pos, range)| {
+                    let range_type = range.item.type_().as_str();
+                    let specificity = if range.item.essence_str() == mime {
+                        2
+                    } else if range.item.subtype() != "*" {
+                        return None;
+                    } else if range_type == type_ {
+                        1
+                    } else if range_type == "*" {
+                        0
+                    } else {
+                        return None;
+                    };
+                    Some((specificity, Reverse(pos), range.quality))
+                })
+                .max()
+                .map(|(_, Reverse(pos), quality)| (Reverse(quality), pos))
+        };
+        FORMATS
+            .iter()
+            .filter_map(|(format, mimes)| {
+                let ranks: Vec<_> = mimes.iter().filter_map(|m| quality(m)).collect();
+                // types with `q=0` are explicitly not acceptable
+                if ranks
+                    .iter()
+                    .any(|(Reverse(quality), _)| *quality == header::Quality::ZERO)
+                {
+                    return None;
+                }
+                Some((ranks.into_iter().min()?, *format))
+            })
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, format)| format)
+    }
+}
 
-    let post_data = data.get_post(id).await;
-    let mut futs = Vec::new();
-    let paragraphs = &post_data.content.body_model.paragraphs;
+#[derive(Deserialize)]
+struct PageQuery {
+    hl: Option<String>,
+    /// Overrides the `Accept` header, see [Format::from_name]
+    format: Option<String>,
+    images: Option<String>,
+}
 
-    for p in paragraphs.iter() {
-        if p.type_ == "IFRAME" {
-            let src = &p
-                .iframe
+/// URLs of the code embeds(see [crate::code_embed]) of a post
+pub fn code_embed_srcs(post: &PostResp) -> Vec<String> {
+    post.content
+        .body_model
+        .paragraphs
+        .iter()
+        .filter(|p| p.type_ == "IFRAME")
+        .map(|p| {
+            p.iframe
                 .as_ref()
                 .unwrap()
                 .media_resource
                 .as_ref()
                 .unwrap()
-                .href;
-            if src.contains("gist.github.com") {
-                let fut = data.get_gist(src.to_owned());
-                futs.push(fut);
+                .href
+                .clone()
+        })
+        .filter(|src| CodeEmbed::classify(src).is_some())
+        .collect()
+}
+
+/// Posts are served as HTML, Markdown, plain text, JSON or EPUB, depending on the
+/// `Accept` header or the `format` query parameter
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.page")]
+async fn page(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<PageQuery>,
+    data: AppData,
+) -> impl Responder {
+    let post_id = get_post_id(&path.1);
+    if post_id.is_none() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let id = post_id.unwrap();
+
+    let (format, negotiated) = match query.format.as_deref() {
+        Some(name) => match Format::from_name(name) {
+            Some(format) => (format, false),
+            None => return HttpResponse::BadRequest().body("Unsupported format"),
+        },
+        None => {
+            let format = match req.get_header::<header::Accept>() {
+                Some(accept) => Format::from_accept(&accept),
+                None => Some(Format::Html),
+            };
+            match format {
+                Some(format) => (format, true),
+                None => {
+                    return HttpResponse::NotAcceptable()
+                        .insert_header((header::VARY, "Accept"))
+                        .body("Supported types: text/html, text/markdown, text/plain, application/json, application/epub+zip")
+                }
             }
         }
+    };
+
+    let mut resp = match format {
+        Format::Html => render_page(&id, query.hl.as_deref(), &data).await,
+        Format::Markdown => export_markdown(&id, query.images.as_deref(), &data).await,
+        Format::Text => match crate::api::get_document(&id, &data).await {
+            Ok(doc) => HttpResponse::Ok()
+                .content_type("text/plain; charset=utf-8")
+                .body(crate::text::render(&doc)),
+            Err(e) => post_error(e),
+        },
+        Format::Json => match crate::api::get_document(&id, &data).await {
+            Ok(doc) => HttpResponse::Ok().json(doc),
+            Err(e) => post_error(e),
+        },
+        Format::Epub => export_epub(&[id], &data).await,
+    };
+    // caches in front of the instance must not mix representations
+    if negotiated {
+        resp.headers_mut()
+            .append(header::VARY, header::HeaderValue::from_static("Accept"));
     }
+    resp
+}
+
+async fn render_page(id: &str, hl: Option<&str>, data: &Data) -> HttpResponse {
+    let post_data = match data.get_post(id).await {
+        Ok(post_data) => post_data,
+        Err(e) => return post_error(e),
+    };
+    let futs: Vec<_> = code_embed_srcs(&post_data)
+        .into_iter()
+        .map(|src| data.get_code_embed(src))
+        .collect();
     let gists = if futs.is_empty() {
         None
     } else {
@@ -221,62 +797,484 @@ async fn page(path: web::Path<(String, String)>, data: AppData) -> impl Responde
         Some(x)
     };
 
-    let date = Utc
-        .timestamp_millis(post_data.created_at)
-        .format("%b %e, %Y")
-        .to_string();
-    let reading_time = post_data.reading_time.floor() as usize;
-    let preview_img = post_data
-        .preview_image
-        .as_ref()
-        .unwrap()
-        .id
-        .as_ref()
-        .unwrap();
-    let preview_img = crate::V1_API_ROUTES.proxy.get_medium_asset(preview_img);
+    let mut post = Post::new(post_data, &gists, data);
+    if let Some(hl) = hl {
+        post.line_highlights = line_highlight_css(hl);
+    }
 
-    let paragraphs = apply_markup(&post_data, &gists);
+    let html = post.render_once().unwrap();
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html)
+}
 
-    let page = Post {
-        id: id.to_owned(),
-        data: post_data,
-        date,
-        gists,
-        reading_time,
-        preview_img,
-        paragraphs,
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// `bundle` to download a zip archive with the post and its images
+    images: Option<String>,
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.markdown")]
+async fn markdown(
+    path: web::Path<(String, String)>,
+    query: web::Query<ExportQuery>,
+    data: AppData,
+) -> impl Responder {
+    match get_post_id(&path.1) {
+        Some(id) => export_markdown(&id, query.images.as_deref(), &data).await,
+        None => HttpResponse::BadRequest().finish(),
+    }
+}
+
+async fn export_markdown(id: &str, images: Option<&str>, data: &Data) -> HttpResponse {
+    let images = match crate::markdown::Images::from_query(images) {
+        Some(images) => images,
+        None => return HttpResponse::BadRequest().body("Unsupported images option"),
     };
 
-    let page = page.render_once().unwrap();
+    let post_data = match data.get_post(id).await {
+        Ok(post_data) => post_data,
+        Err(e) => return post_error(e),
+    };
+    let futs: Vec<_> = code_embed_srcs(&post_data)
+        .into_iter()
+        .map(|src| data.get_code_embed_source(src))
+        .collect();
+    let code_embeds = if futs.is_empty() {
+        None
+    } else {
+        Some(join_all(futs).await)
+    };
+
+    let md = crate::markdown::render(&post_data, &code_embeds, images);
+    let slug = &post_data.unique_slug;
+    if images == crate::markdown::Images::Instance {
+        return HttpResponse::Ok()
+            .content_type("text/markdown; charset=utf-8")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("inline; filename=\"{slug}.md\""),
+            ))
+            .body(md.text);
+    }
+
+    match crate::markdown::bundle(&md, slug, data).await {
+        Ok(zip) => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{slug}.zip\""),
+            ))
+            .body(zip),
+        Err(e) => {
+            log::error!("couldn't bundle post {id}: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Posts that can be packaged in one EPUB
+const EPUB_BATCH_LIMIT: usize = 20;
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.epub")]
+async fn epub(path: web::Path<(String, String)>, data: AppData) -> impl Responder {
+    match get_post_id(&path.1) {
+        Some(id) => export_epub(&[id], &data).await,
+        None => HttpResponse::BadRequest().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct EpubBatchQuery {
+    /// Comma separated post IDs or URLs
+    posts: String,
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.epub_batch")]
+async fn epub_batch(query: web::Query<EpubBatchQuery>, data: AppData) -> impl Responder {
+    let ids: Option<Vec<String>> = query
+        .posts
+        .split(',')
+        .filter(|post| !post.trim().is_empty())
+        .map(|post| get_post_id(post.trim()))
+        .collect();
+    match ids {
+        Some(ids) if ids.is_empty() => HttpResponse::BadRequest().body("No posts"),
+        Some(ids) if ids.len() > EPUB_BATCH_LIMIT => HttpResponse::BadRequest().body(format!(
+            "At most {EPUB_BATCH_LIMIT} posts can be exported at once"
+        )),
+        Some(ids) => export_epub(&ids, &data).await,
+        None => HttpResponse::BadRequest().body("Invalid post ID"),
+    }
+}
+
+async fn export_epub(ids: &[String], data: &Data) -> HttpResponse {
+    let posts = match join_all(ids.iter().map(|id| data.get_post(id)))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(posts) => posts,
+        Err(e) => return post_error(e),
+    };
+    let file_name = match posts.as_slice() {
+        [post] => format!("{}.epub", post.unique_slug),
+        _ => "libmedium.epub".to_owned(),
+    };
+
+    match crate::epub::build(&posts, data).await {
+        Ok(book) => HttpResponse::Ok()
+            .content_type("application/epub+zip")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            ))
+            .body(book),
+        Err(e) => {
+            log::error!("couldn't package posts {ids:?}: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[actix_web_codegen_const_routes::get(path = "crate::V1_API_ROUTES.proxy.responses")]
+async fn post_responses(path: web::Path<(String, String)>, data: AppData) -> impl Responder {
+    let post_id = get_post_id(&path.1);
+    if post_id.is_none() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let id = post_id.unwrap();
+
+    let responses = match data.get_responses(&id).await {
+        Ok(responses) => responses,
+        Err(FetchError::NotFound) => return HttpResponse::NotFound().body("Post not found"),
+        Err(FetchError::Upstream) => {
+            return HttpResponse::BadGateway().body("Couldn't fetch responses from Medium")
+        }
+    };
+    let post_data = match data.get_post(&id).await {
+        Ok(post_data) => post_data,
+        Err(e) => return post_error(e),
+    };
+
+    let html = Responses {
+        data: post_data,
+        responses: responses
+            .iter()
+            .map(|r| RenderedResponse::new(r, data.as_ref()))
+            .collect(),
+    }
+    .render_once()
+    .unwrap();
+
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(page)
+        .body(html)
 }
 
 pub fn services(cfg: &mut web::ServiceConfig) {
     cfg.service(by_post_id);
     cfg.service(assets);
+    cfg.service(external_assets);
+    cfg.service(media);
+    cfg.service(syntax_css);
+    cfg.service(syntax_theme);
+    cfg.service(gist_raw);
+    cfg.service(post_responses);
+    cfg.service(markdown);
+    cfg.service(epub);
+    cfg.service(epub_batch);
     cfg.service(page);
+    cfg.service(resolve);
+    cfg.service(by_user_id);
+    cfg.service(author);
     cfg.service(by_top_level_post);
     cfg.service(index);
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use actix_web::{http::StatusCode, test, App};
 
     use super::*;
     use crate::{routes::services, Data};
 
+    /// The post in `tests/post.json`, shared by tests of every module that renders posts
+    pub fn post() -> PostResp {
+        serde_json::from_str(include_str!("../tests/post.json")).unwrap()
+    }
+
+    /// Collect values of `src` and `srcset` attributes that point outside of this instance
+    fn third_party_srcs(html: &str) -> Vec<&str> {
+        let mut srcs = Vec::default();
+        for attr in ["src=\"", "srcset=\""] {
+            for (start, _) in html.match_indices(attr) {
+                if !html[..start].ends_with(|c: char| c.is_ascii_whitespace()) {
+                    continue;
+                }
+                let value = &html[start + attr.len()..];
+                let value = &value[..value.find('"').unwrap()];
+                if value
+                    .split(',')
+                    .any(|src| !src.trim().starts_with('/') || src.trim().starts_with("//"))
+                {
+                    srcs.push(value);
+                }
+            }
+        }
+        srcs
+    }
+
+    #[actix_rt::test]
+    async fn responses_work() {
+        let post = post();
+        let res: crate::data::get_post_responses::ResponseData =
+            serde_json::from_str(include_str!("../tests/responses.json")).unwrap();
+        let responses = PostResponse::threads(res.post.unwrap());
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].replies.len(), 1);
+
+        let html = Responses {
+            data: post,
+            responses: responses
+                .iter()
+                .map(|r| RenderedResponse::new(r, &crate::post::Uncached))
+                .collect(),
+        }
+        .render_once()
+        .unwrap();
+        assert!(html.contains("the <strong>epoch section</strong> was"));
+        // mixtapes are selected in responses too
+        assert!(html
+            .contains(r#"<a class="mixtape" rel="noreferrer" href="/utils/post/b62607a43a8c">"#));
+        let reply = html.find("response__reply").unwrap();
+        assert!(html[reply..].contains("Thanks, glad it helped!"));
+    }
+
+    #[actix_rt::test]
+    async fn images_are_proxied() {
+        let post = post();
+        let html = Post::new(post, &None, &crate::post::Uncached)
+            .render_once()
+            .unwrap();
+        assert!(html.contains("<img"));
+        assert!(html.contains("srcset=\""));
+        assert_eq!(third_party_srcs(&html), Vec::<&str>::default());
+    }
+
+    #[actix_rt::test]
+    async fn format_date_works() {
+        assert_eq!(format_date(1506533400000), "Sep 27, 2017");
+        assert_eq!(format_date(i64::MAX), "");
+    }
+
+    #[actix_rt::test]
+    async fn asset_width_allowlist_works() {
+        let data = Data::temporary();
+        let app = test::init_service(App::new().app_data(data.clone()).configure(services)).await;
+        let url = format!(
+            "{}?w=123",
+            crate::V1_API_ROUTES
+                .proxy
+                .get_medium_asset("1*LY2ohYsNa9nOV1Clko3zJA.png")
+        );
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn content_negotiation_works() {
+        let accept = |value: &str| {
+            let req = test::TestRequest::default()
+                .insert_header((header::ACCEPT, value))
+                .to_http_request();
+            Format::from_accept(&req.get_header::<header::Accept>().unwrap())
+        };
+        assert_eq!(
+            accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+            Some(Format::Html)
+        );
+        assert_eq!(accept("*/*"), Some(Format::Html));
+        assert_eq!(accept("text/markdown"), Some(Format::Markdown));
+        assert_eq!(
+            accept("text/html;q=0.5, application/json"),
+            Some(Format::Json)
+        );
+        assert_eq!(accept("image/png, text/plain;q=0.1"), Some(Format::Text));
+        assert_eq!(accept("application/epub+zip"), Some(Format::Epub));
+        assert_eq!(accept("image/png"), None);
+        assert_eq!(accept("text/*, text/html;q=0"), Some(Format::Markdown));
+        assert_eq!(accept("text/plain, text/markdown"), Some(Format::Text));
+        assert_eq!(accept("text/*;q=0.5, text/plain"), Some(Format::Text));
+        assert_eq!(
+            accept("*/*;q=0.1, application/json;q=0"),
+            Some(Format::Html)
+        );
+        assert_eq!(accept("text/html;q=0, */*"), Some(Format::Markdown));
+        assert_eq!(
+            accept("text/*, text/html;q=0, text/markdown;q=0, text/plain;q=0"),
+            None
+        );
+        assert_eq!(accept("*/*;q=0"), None);
+        assert_eq!(Format::from_name("md"), Some(Format::Markdown));
+        assert_eq!(Format::from_name("pdf"), None);
+
+        let data = Data::temporary();
+        let app = test::init_service(App::new().app_data(data.clone()).configure(services)).await;
+        let url = crate::V1_API_ROUTES.proxy.get_page(
+            "tylerneely",
+            "fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+        );
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&url)
+                .insert_header((header::ACCEPT, "image/png"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("{url}?format=pdf"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn syntax_theme_works() {
+        let data = Data::temporary();
+        let app = test::init_service(App::new().app_data(data.clone()).configure(services)).await;
+        let proxy = &crate::V1_API_ROUTES.proxy;
+
+        let req = test::TestRequest::get()
+            .uri(&proxy.get_syntax_theme("dark"))
+            .insert_header((header::REFERER, "https://evil.example.com/@tylerneely?x=1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            "/@tylerneely?x=1"
+        );
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        assert_eq!(cookie.value(), "dark");
+
+        for referer in [
+            "https://libmedium.example.com//evil.example.com/@tylerneely",
+            "https://libmedium.example.com/\\evil.example.com/@tylerneely",
+            "https://libmedium.example.com/%2F/evil.example.com",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&proxy.get_syntax_theme("dark"))
+                .insert_header((header::REFERER, referer))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            let location = resp.headers().get(header::LOCATION).unwrap();
+            assert!(
+                !location.to_str().unwrap().starts_with("//")
+                    && !location.to_str().unwrap().starts_with("/\\"),
+                "{referer}: {location:?}"
+            );
+        }
+        let req = test::TestRequest::get()
+            .uri(&proxy.get_syntax_theme("dark"))
+            .insert_header((
+                header::REFERER,
+                "https://libmedium.example.com//evil.example.com",
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), proxy.index);
+
+        let req = test::TestRequest::get()
+            .uri(proxy.syntax_css)
+            .cookie(cookie)
+            .to_request();
+        let css = test::call_and_read_body(&app, req).await;
+        assert_eq!(css, THEME_CSS.get(ThemeMode::Dark).as_bytes());
+
+        let req = test::TestRequest::get().uri(proxy.syntax_css).to_request();
+        let css = test::call_and_read_body(&app, req).await;
+        assert_eq!(css, THEME_CSS.get(ThemeMode::Auto).as_bytes());
+
+        let req = test::TestRequest::get()
+            .uri(&proxy.get_syntax_theme("sepia"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn gist_raw_works() {
+        let data = Data::temporary();
+        let app = test::init_service(App::new().app_data(data.clone()).configure(services)).await;
+        let proxy = &crate::V1_API_ROUTES.proxy;
+
+        const ID: &str = "0badc0de";
+        const REVISION: &str = "c0ffee";
+        let gist = crate::data::GistContent {
+            files: vec![crate::data::GistFile {
+                file_name: "hello world.html".into(),
+                content: "<script>alert(1)</script>".into(),
+                language: "HTML".into(),
+                raw_url:
+                    "https://gist.githubusercontent.com/a/0badc0de/raw/c0ffee/hello%20world.html"
+                        .into(),
+            }],
+            html_url: "https://gist.github.com/0badc0de".into(),
+            cached: false,
+        };
+        data.gists
+            .insert(
+                format!("{ID}/{REVISION}"),
+                bincode::serialize(&gist).unwrap(),
+            )
+            .unwrap();
+
+        let url = proxy.get_gist_raw(ID, "hello world.html", Some(REVISION));
+        assert_eq!(url, "/gist/0badc0de/hello%20world.html/raw?revision=c0ffee");
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            resp.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        let body = test::read_body(resp).await;
+        assert_eq!(body, "<script>alert(1)</script>");
+
+        // other revisions aren't cached
+        for url in [
+            proxy.get_gist_raw(ID, "hello world.html", None),
+            proxy.get_gist_raw(ID, "missing.rs", Some(REVISION)),
+        ] {
+            let resp =
+                test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
     #[actix_rt::test]
     async fn deploy_update_works() {
-        let data = Data::new();
+        let data = Data::temporary();
         let app = test::init_service(App::new().app_data(data.clone()).configure(services)).await;
-        let urls = vec![
+        let urls = [
             "/@ftrain/big-data-small-effort-b62607a43a8c",
             "/@shawn-shi/rest-api-best-practices-decouple-long-running-tasks-from-http-request-processing-9fab2921ace8",
+            "/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c/responses",
+            "/@tylerneely",
             "/",
             "/asset/medium/1*LY2ohYsNa9nOV1Clko3zJA.png",
+            "/asset/medium/1*LY2ohYsNa9nOV1Clko3zJA.png?w=640",
         ];
 
         for uri in urls.iter() {
@@ -285,15 +1283,15 @@ mod tests {
             assert_eq!(resp.status(), StatusCode::OK);
         }
 
-        let urls = vec![
+        let urls = [
             "/ftrain/big-data-small-effort-b62607a43a8c",
             "/shawn-shi/rest-api-best-practices-decouple-long-running-tasks-from-http-request-processing-9fab2921ace8",
         ];
 
         for uri in urls.iter() {
-            let id = uri.split('-').last().unwrap();
+            let id = get_post_id(uri).unwrap();
 
-            let page_url = crate::V1_API_ROUTES.proxy.by_post_id.replace("{post}", id);
+            let page_url = crate::V1_API_ROUTES.proxy.get_by_post_id(&id);
 
             let resp =
                 test::call_service(&app, test::TestRequest::get().uri(&page_url).to_request())
@@ -309,6 +1307,18 @@ mod tests {
             assert_eq!(resp.status(), StatusCode::OK);
             let res = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
             assert!(res.contains(include_str!("../tests/7158b1cdd50c.html")));
+            assert_eq!(third_party_srcs(&res), Vec::<&str>::default());
         }
+
+        let url = format!(
+            "{}?url=https://medium.com/p/7158b1cdd50c",
+            crate::V1_API_ROUTES.proxy.resolve
+        );
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            "/tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c"
+        );
     }
 }
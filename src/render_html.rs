@@ -16,78 +16,329 @@
  */
 use std::path::Path;
 
-use syntect::highlighting::{Color, ThemeSet};
-use syntect::html::highlighted_html_for_string;
-use syntect::parsing::{SyntaxReference, SyntaxSet};
+use lazy_static::lazy_static;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{css_for_theme_with_class_style, line_tokens_to_classed_spans, ClassStyle};
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+use syntect::LoadingError;
 
-pub trait GenerateHTML {
-    fn generate(&mut self);
+use crate::settings::{SyntaxOverride, SyntaxPrefix, SyntaxThemes};
+use crate::SETTINGS;
+
+/// Highlighted code is tagged with prefixed classes so that scope names like
+/// `line` don't clash with the page's own classes
+const PREFIX: &str = "syn-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: PREFIX };
+
+lazy_static! {
+    /// Syntaxes and themes are dumped by build.rs and loaded once, for all workers
+    pub static ref SYNTAX_SET: SyntaxSet = load_syntax_set(SETTINGS.syntaxes_dir.as_deref())
+        .unwrap_or_else(|e| panic!("couldn't load syntaxes from syntaxes_dir: {e}"));
+    pub static ref THEME_SET: ThemeSet = syntect::dumps::from_binary(include_bytes!(concat!(
+        env!("OUT_DIR"),
+        "/themes.themedump"
+    )));
+    /// Stylesheets of the configured themes, generated at startup
+    pub static ref THEME_CSS: ThemeCss = ThemeCss::new(&SETTINGS.syntax_themes);
+    /// Configured extension overrides take precedence over the built-in ones
+    static ref SYNTAX_OVERRIDES: Vec<SyntaxOverride> = SETTINGS
+        .syntax_overrides
+        .iter()
+        .cloned()
+        .chain(builtin_overrides())
+        .collect();
+}
+
+/// Load syntaxes dumped by build.rs, along with the ones in `dir`
+pub fn load_syntax_set(dir: Option<&str>) -> Result<SyntaxSet, LoadingError> {
+    let syntax_set: SyntaxSet = syntect::dumps::from_uncompressed_data(include_bytes!(concat!(
+        env!("OUT_DIR"),
+        "/syntaxes.packdump"
+    )))
+    .unwrap();
+    match dir {
+        Some(dir) => merge_syntaxes(syntax_set, dir),
+        None => Ok(syntax_set),
+    }
+}
+
+/// Digest of the configured syntaxes: the contents of `.sublime-syntax` files in
+/// `dir` and the extension overrides. Highlighted code is memoised, so the memo
+/// must be cleared when this changes.
+pub fn syntax_fingerprint(dir: Option<&str>, overrides: &[SyntaxOverride]) -> String {
+    fn syntax_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.is_dir() {
+                syntax_files(&path, files);
+            } else if path.extension().is_some_and(|e| e == "sublime-syntax") {
+                files.push(path);
+            }
+        }
+    }
+
+    let mut fingerprint = format!("{overrides:?}");
+    if let Some(dir) = dir {
+        let mut files = Vec::default();
+        syntax_files(Path::new(dir), &mut files);
+        files.sort();
+        for file in files {
+            let content = std::fs::read_to_string(&file).unwrap_or_default();
+            fingerprint += &format!("\n{}\n{}", file.display(), sha256::digest(content));
+        }
+    }
+    sha256::digest(fingerprint)
+}
+
+/// Merge `.sublime-syntax` files from `dir` into `syntax_set`
+fn merge_syntaxes(syntax_set: SyntaxSet, dir: &str) -> Result<SyntaxSet, LoadingError> {
+    let mut builder = syntax_set.into_builder();
+    builder.add_from_folder(dir, true)?;
+    let syntax_set = builder.build();
+    log::info!(
+        "loaded {} syntaxes, including ones from {dir}",
+        syntax_set.syntaxes().len()
+    );
+    Ok(syntax_set)
+}
+
+/// Override syntect's language detection for conflicting file extensions because
+/// it's impossible to express this logic in a syntax definition.
+fn builtin_overrides() -> Vec<SyntaxOverride> {
+    let prefix = |prefix: &str, syntax: &str| SyntaxPrefix {
+        prefix: prefix.into(),
+        syntax: syntax.into(),
+    };
+    vec![SyntaxOverride {
+        extension: "cls".into(),
+        prefixes: vec![prefix("%", "TeX"), prefix("\\", "TeX")],
+        default: "Apex".into(),
+    }]
+}
+
+impl SyntaxOverride {
+    /// Name of the syntax to use for `code`
+    fn syntax_name(&self, code: &str) -> &str {
+        self.prefixes
+            .iter()
+            .find(|p| code.starts_with(p.prefix.as_str()))
+            .map(|p| p.syntax.as_str())
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Theme preference of a reader
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThemeMode {
+    /// Follow `prefers-color-scheme`
+    #[default]
+    Auto,
+    Light,
+    Dark,
+}
+
+impl ThemeMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Self::Auto),
+            "light" => Some(Self::Light),
+            "dark" => Some(Self::Dark),
+            _ => None,
+        }
+    }
 }
 
-#[allow(dead_code)]
-pub const STYLE: &str = "
-    ";
+pub struct ThemeCss {
+    light: String,
+    dark: String,
+    auto: String,
+}
+
+impl ThemeCss {
+    pub fn new(themes: &SyntaxThemes) -> Self {
+        let css = |name: &str| {
+            let theme = THEME_SET
+                .themes
+                .get(name)
+                .unwrap_or_else(|| panic!("syntax theme {name} not found"));
+            css_for_theme_with_class_style(theme, CLASS_STYLE).unwrap()
+        };
+        let light = css(&themes.light);
+        let dark = css(&themes.dark);
+        let auto = format!("{light}\n@media (prefers-color-scheme: dark) {{\n{dark}\n}}\n");
+        Self { light, dark, auto }
+    }
 
-thread_local! {
-    pub(crate) static SYNTAX_SET: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    pub fn get(&self, mode: ThemeMode) -> &str {
+        match mode {
+            ThemeMode::Auto => &self.auto,
+            ThemeMode::Light => &self.light,
+            ThemeMode::Dark => &self.dark,
+        }
+    }
+}
+
+/// Background of highlighted lines, kept in sync with `.line:target` in main.css
+const LINE_HIGHLIGHT: &str = "rgba(255, 213, 0, 0.25)";
+
+/// Generate CSS that highlights line ranges requested with the `hl` query parameter.
+/// A range is either `{start}[-{end}]`, which applies to all code blocks, or
+/// `{block_id}-L{start}[-L{end}]`, which applies to one block. Multiple ranges
+/// are separated by commas: `?hl=3,code-12-L10-L20`.
+pub fn line_highlight_css(hl: &str) -> String {
+    fn parse_range(range: &str) -> Option<(usize, usize)> {
+        let mut bounds = range.splitn(2, '-');
+        let start: usize = bounds.next()?.trim_start_matches('L').parse().ok()?;
+        let end: usize = match bounds.next() {
+            Some(end) => end.trim_start_matches('L').parse().ok()?,
+            None => start,
+        };
+        if start == 0 || end < start {
+            return None;
+        }
+        Some((start, end))
+    }
+
+    let mut selectors = Vec::default();
+    for range in hl.split(',').map(|r| r.trim()) {
+        let (block, range) = match range.find("-L") {
+            Some(pos) => (Some(&range[..pos]), &range[pos + 1..]),
+            None => (None, range),
+        };
+        let block = match block {
+            Some(block)
+                if !block.is_empty()
+                    && block
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') =>
+            {
+                // IDs can start with digits, which ID selectors don't allow
+                format!(r#"pre[id="{block}"]"#)
+            }
+            Some(_) => continue,
+            None => format!(".{PREFIX}code"),
+        };
+        if let Some((start, end)) = parse_range(range) {
+            selectors.push(format!(
+                "{block} .line:nth-child(n+{start}):nth-child(-n+{end})"
+            ));
+        }
+    }
+
+    if selectors.is_empty() {
+        String::default()
+    } else {
+        format!(
+            "{} {{ background-color: {LINE_HIGHLIGHT}; }}",
+            selectors.join(", ")
+        )
+    }
 }
 
 pub struct SourcegraphQuery<'a> {
     pub filepath: &'a str,
     pub code: &'a str,
+    /// Language hint, like the one Medium stores for code blocks
+    pub lang: Option<&'a str>,
 }
 
 impl<'a> SourcegraphQuery<'a> {
-    pub fn syntax_highlight(&self, gist_name: &str) -> String {
-        //    let ss = SYNTAX_SET;
-        let ts = ThemeSet::load_defaults();
-
-        let theme = &ts.themes["InspiredGitHub"];
-        let c = theme.settings.background.unwrap_or(Color::WHITE);
-        let mut num = 1;
-        let mut output = format!(
-            "<style>
-        .gist_file {{
-            background-color:#{:02x}{:02x}{:02x};
-        }}</style>",
-            c.r, c.g, c.b
-        );
+    /// Highlighted code block with numbered lines. `block_id` must be made of lowercase
+    /// alphanumeric characters and dashes; lines get `{block_id}-L{n}` IDs, which is
+    /// what [line_highlight_css] expects.
+    pub fn syntax_highlight(&self, block_id: &str) -> String {
+        let language = self.determine_language(&SYNTAX_SET);
+        let lines = Self::classed_lines(self.code, language, &SYNTAX_SET);
+
+        let mut output = format!(r#"<pre class="{PREFIX}code" id="{block_id}">"#);
+        for (index, line) in lines.iter().enumerate() {
+            let num = index + 1;
+            let line_id = format!("{block_id}-L{num}");
+            // clicking on a line number highlights the line and links to it
+            output.push_str(&format!(
+                r##"<span class="line" id="{line_id}"><a class="line-number" href="?hl={line_id}#{line_id}">{num}</a><span class="line-content">{line}</span></span>"##
+            ));
+        }
+        output.push_str("</pre>");
+        output
+    }
+
+    /// Name of the language of the code, as used in Markdown code fences. `None`
+    /// for plain text.
+    pub fn language(&self) -> Option<String> {
+        let syntax = self.determine_language(&SYNTAX_SET);
+        if syntax.name == SYNTAX_SET.find_syntax_plain_text().name {
+            return None;
+        }
+        // names like "Bourne Again Shell (bash)" can't be used in fences
+        match syntax.file_extensions.first() {
+            Some(extension) if syntax.name.contains(char::is_whitespace) => {
+                Some(extension.to_owned())
+            }
+            _ => Some(syntax.name.to_lowercase()),
+        }
+    }
 
-        // highlighted_html_for_string(&q.code, syntax_set, syntax_def, theme),
-        let html = SYNTAX_SET
-            .with(|ss| {
-                let language = self.determine_language(ss);
-                highlighted_html_for_string(self.code, ss, language, theme)
-            })
-            .unwrap();
-        let total_lines = html.lines().count();
-        for (line_num, line) in html.lines().enumerate() {
-            if !line.trim().is_empty() {
-                if line_num == 0 || line_num == total_lines - 1 {
-                    output.push_str(line);
-                } else {
-                    let line_id = format!("{gist_name}-{num}");
-                    output.push_str(&format!("<div title='click for more options' id=\"line-{line_id}\"class=\"line\"><details class='line_links'><summary class='line_top-link'><a href=\"#line-{line_id}\"<span class=\"line-number\">{num}</span></a>{line}</summary><a href=\"#line-{line_id}\"<span class=\"line-link\">Permanant link</span></a><a href=\"#line-{line_id}\"<span class=\"line-link\">Highlight</span></a></details></div>"
-                    ));
-                    num += 1;
+    /// Highlight `code` line by line. Scopes can span multiple lines(block comments,
+    /// strings), so spans that are open at the end of a line are closed and reopened
+    /// on the next one to keep every line self-contained.
+    fn classed_lines(code: &str, syntax: &SyntaxReference, ss: &SyntaxSet) -> Vec<String> {
+        let mut parse_state = ParseState::new(syntax);
+        let mut stack = ScopeStack::new();
+        let mut lines = Vec::default();
+        for line in LinesWithEndings::from(code) {
+            let mut html: String = stack.as_slice().iter().map(|s| Self::span(*s)).collect();
+            let spans = parse_state.parse_line(line, ss).ok().and_then(|ops| {
+                line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut stack).ok()
+            });
+            match spans {
+                Some((spans, _)) => html.push_str(&spans.replace('\n', "")),
+                None => {
+                    let mut escaped = String::default();
+                    sailfish::runtime::escape::escape_to_string(
+                        line.trim_end_matches('\n'),
+                        &mut escaped,
+                    );
+                    html.push_str(&escaped);
                 }
             }
+            for _ in stack.as_slice() {
+                html.push_str("</span>");
+            }
+            lines.push(html);
         }
-        output
+        lines
+    }
+
+    fn span(scope: Scope) -> String {
+        let classes: Vec<String> = scope
+            .build_string()
+            .split('.')
+            .map(|atom| format!("{PREFIX}{atom}"))
+            .collect();
+        format!(r#"<span class="{}">"#, classes.join(" "))
     }
 
     // adopted from
     // https://github.com/sourcegraph/sourcegraph/blob/9fe138ae75fd64dce06b621572b252a9c9c8da70/docker-images/syntax-highlighter/crates/sg-syntax/src/lib.rs#L81
     // with minimum modifications. Crate was MIT licensed at the time(2022-03-12 11:11)
     fn determine_language<'b>(&self, syntax_set: &'b SyntaxSet) -> &'b SyntaxReference {
+        if let Some(syntax) = self
+            .lang
+            .and_then(|lang| self.find_by_lang(syntax_set, lang))
+        {
+            return syntax;
+        }
+
         if self.filepath.is_empty() {
             // Legacy codepath, kept for backwards-compatability with old clients.
-            match syntax_set.find_syntax_by_first_line(self.code) {
-                Some(v) => {
-                    return v;
-                }
-                None => unimplemented!(), //Err(json!({"error": "invalid extension"})),
-            };
+            return syntax_set
+                .find_syntax_by_first_line(self.code)
+                .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
         }
 
         // Split the input path ("foo/myfile.go") into file name
@@ -96,35 +347,8 @@ impl<'a> SourcegraphQuery<'a> {
         let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
         let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
 
-        // Override syntect's language detection for conflicting file extensions because
-        // it's impossible to express this logic in a syntax definition.
-        struct Override {
-            extension: &'static str,
-            prefix_langs: Vec<(&'static str, &'static str)>,
-            default: &'static str,
-        }
-        let overrides = vec![Override {
-            extension: "cls",
-            prefix_langs: vec![("%", "TeX"), ("\\", "TeX")],
-            default: "Apex",
-        }];
-
-        if let Some(Override {
-            prefix_langs,
-            default,
-            ..
-        }) = overrides.iter().find(|o| o.extension == extension)
-        {
-            let name = match prefix_langs
-                .iter()
-                .find(|(prefix, _)| self.code.starts_with(prefix))
-            {
-                Some((_, lang)) => lang,
-                None => default,
-            };
-            return syntax_set
-                .find_syntax_by_name(name)
-                .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+        if let Some(syntax) = self.find_by_override(syntax_set, extension) {
+            return syntax;
         }
 
         syntax_set
@@ -137,13 +361,57 @@ impl<'a> SourcegraphQuery<'a> {
             .or_else(|| syntax_set.find_syntax_by_first_line(self.code))
             .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
     }
+
+    /// Find syntax of an extension listed in [SYNTAX_OVERRIDES]
+    fn find_by_override<'b>(
+        &self,
+        syntax_set: &'b SyntaxSet,
+        extension: &str,
+    ) -> Option<&'b SyntaxReference> {
+        let name = SYNTAX_OVERRIDES
+            .iter()
+            .find(|o| o.extension == extension)?
+            .syntax_name(self.code);
+        Some(
+            syntax_set
+                .find_syntax_by_name(name)
+                .unwrap_or_else(|| syntax_set.find_syntax_plain_text()),
+        )
+    }
+
+    /// Find syntax by language name(`rust`) or extension(`rs`)
+    fn find_by_lang<'b>(
+        &self,
+        syntax_set: &'b SyntaxSet,
+        lang: &str,
+    ) -> Option<&'b SyntaxReference> {
+        // names that Medium uses which don't match syntect's
+        const ALIASES: [(&str, &str); 4] = [
+            ("csharp", "cs"),
+            ("shell", "sh"),
+            ("objectivec", "m"),
+            ("plaintext", "txt"),
+        ];
+        let lang = lang.trim().to_lowercase();
+        let lang = ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == lang)
+            .map(|(_, token)| *token)
+            .unwrap_or(&lang);
+        self.find_by_override(syntax_set, lang)
+            .or_else(|| syntax_set.find_syntax_by_token(lang))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SourcegraphQuery;
+    use super::{
+        builtin_overrides, merge_syntaxes, syntax_fingerprint, SourcegraphQuery, ThemeCss,
+        ThemeMode,
+    };
+    use crate::settings::SyntaxThemes;
 
-    use syntect::parsing::SyntaxSet;
+    use syntect::parsing::{SyntaxDefinition, SyntaxSet, SyntaxSetBuilder};
 
     #[test]
     fn cls_tex() {
@@ -151,12 +419,143 @@ mod tests {
         let query = SourcegraphQuery {
             filepath: "foo.cls",
             code: "%",
+            lang: None,
         };
         let result = query.determine_language(&syntax_set);
         assert_eq!(result.name, "TeX");
         let _result = query.syntax_highlight("foo");
     }
 
+    #[test]
+    fn lang_hint() {
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let langs = [
+            (Some("rust"), "fn main() {}", "Rust"),
+            (Some("csharp"), "var x = 1;", "C#"),
+            (Some("unknown"), "#!/usr/bin/env python\nprint(1)", "Python"),
+            (None, "#!/bin/bash\necho 1", "Bourne Again Shell (bash)"),
+            (None, "just some text", "Plain Text"),
+        ];
+        for (lang, code, name) in langs.iter() {
+            let query = SourcegraphQuery {
+                filepath: "",
+                code,
+                lang: *lang,
+            };
+            assert_eq!(query.determine_language(&syntax_set).name, *name);
+        }
+    }
+
+    #[test]
+    fn syntaxes_dir_works() {
+        const TOML: &str = r#"%YAML 1.2
+---
+name: TOML
+file_extensions: [toml]
+scope: source.toml
+contexts:
+  main:
+    - match: '#.*$'
+      scope: comment.line.toml
+"#;
+        const ZIG: &str = r#"%YAML 1.2
+---
+name: Zig
+file_extensions: [zig]
+scope: source.zig
+contexts:
+  main:
+    - match: '\b(fn|const|pub)\b'
+      scope: keyword.zig
+"#;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("Zig.sublime-syntax"), ZIG).unwrap();
+
+        let query = SourcegraphQuery {
+            filepath: "main.zig",
+            code: "pub fn main() void {}\n",
+            lang: Some("zig"),
+        };
+        // merging into the default set works the same way, but linking it is slow
+        let mut base = SyntaxSetBuilder::new();
+        base.add(SyntaxDefinition::load_from_str(TOML, true, None).unwrap());
+        base.add_plain_text_syntax();
+        let base = base.build();
+        assert_eq!(query.determine_language(&base).name, "Plain Text");
+
+        let syntax_set = merge_syntaxes(base, dir.to_str().unwrap()).unwrap();
+        assert_eq!(query.determine_language(&syntax_set).name, "Zig");
+        assert!(syntax_set.find_syntax_by_name("TOML").is_some());
+
+        let fingerprint = syntax_fingerprint(dir.to_str(), &[]);
+        assert_eq!(fingerprint, syntax_fingerprint(dir.to_str(), &[]));
+        assert_ne!(fingerprint, syntax_fingerprint(None, &[]));
+        assert_ne!(
+            fingerprint,
+            syntax_fingerprint(dir.to_str(), &builtin_overrides())
+        );
+        std::fs::write(dir.join("Zig.sublime-syntax"), ZIG.replace("pub", "export")).unwrap();
+        assert_ne!(fingerprint, syntax_fingerprint(dir.to_str(), &[]));
+    }
+
+    #[test]
+    fn classed_lines_are_balanced() {
+        let query = SourcegraphQuery {
+            filepath: "main.rs",
+            code: "/* a comment\nthat spans lines */\nfn main() {}\n",
+            lang: None,
+        };
+        let html = query.syntax_highlight("foo");
+        assert!(!html.contains("style="));
+        assert!(html.contains(r#"class="syn-comment syn-block syn-rust""#));
+        assert!(html.starts_with(r#"<pre class="syn-code" id="foo">"#));
+        assert!(html.contains(r##"<a class="line-number" href="?hl=foo-L2#foo-L2">2</a>"##));
+        for line in html.split(r#"<span class="line" id="#).skip(1) {
+            // the line wrapper's opening tag is consumed by split
+            assert_eq!(
+                line.matches("<span").count() + 1,
+                line.matches("</span>").count()
+            );
+        }
+
+        let css = ThemeCss::new(&SyntaxThemes::default());
+        assert!(css.get(ThemeMode::Light).contains(".syn-code"));
+        assert!(css
+            .get(ThemeMode::Auto)
+            .contains("@media (prefers-color-scheme: dark)"));
+        assert!(!css.get(ThemeMode::Dark).contains("@media"));
+    }
+
+    #[test]
+    fn line_highlight_works() {
+        let ranges = [
+            ("3", ".syn-code .line:nth-child(n+3):nth-child(-n+3)"),
+            ("10-20", ".syn-code .line:nth-child(n+10):nth-child(-n+20)"),
+            (
+                "code-12-L4",
+                r#"pre[id="code-12"] .line:nth-child(n+4):nth-child(-n+4)"#,
+            ),
+            (
+                "code-12-L4-L6,1",
+                r#"pre[id="code-12"] .line:nth-child(n+4):nth-child(-n+6), .syn-code .line:nth-child(n+1):nth-child(-n+1)"#,
+            ),
+        ];
+        for (hl, selectors) in ranges.iter() {
+            assert_eq!(
+                super::line_highlight_css(hl),
+                format!(
+                    "{selectors} {{ background-color: {}; }}",
+                    super::LINE_HIGHLIGHT
+                )
+            );
+        }
+
+        for hl in ["", "0", "20-10", "x", "code}-L1", "</style>-L1", "Code-L1"] {
+            assert_eq!(super::line_highlight_css(hl), "", "{hl}");
+        }
+    }
+
     //#[test]
     //fn cls_apex() {
     //    let syntax_set = SyntaxSet::load_defaults_newlines();
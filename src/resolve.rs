@@ -0,0 +1,294 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use url::Url;
+
+use crate::{SETTINGS, V1_API_ROUTES};
+
+/// Subdomains of medium.com that don't belong to users
+const RESERVED_SUBDOMAINS: [&str; 7] = ["www", "link", "help", "policy", "blog", "miro", "cdn"];
+
+/// Medium post IDs are 10-12 character long lowercase hex strings
+pub fn is_post_id(id: &str) -> bool {
+    (10..=12).contains(&id.len()) && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Extract post ID from any of the URL shapes that Medium uses:
+///
+/// - bare post IDs: `7158b1cdd50c`
+/// - slugs: `fear-and-loathing-in-lock-free-programming-7158b1cdd50c`
+/// - paths: `/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c`
+/// - URLs on medium.com, its subdomains and custom domains, with or without query params
+/// - short URLs: `https://medium.com/p/7158b1cdd50c`
+/// - sign-in redirects: `https://medium.com/m/global-identity?redirectUrl=<url>`
+///
+/// Short links(`link.medium.com`) and slugs without a trailing ID can't be
+/// resolved without talking to Medium, see [crate::Data::resolve_post_id]
+pub fn get_post_id(input: &str) -> Option<String> {
+    let input = input.trim();
+    if is_post_id(input) {
+        return Some(input.to_owned());
+    }
+
+    let url = match Url::parse(input) {
+        Ok(url) if url.has_host() => Some(url),
+        _ => {
+            let host = input.split('/').next().unwrap_or_default();
+            if host.contains('.') && !host.starts_with('.') {
+                Url::parse(&format!("https://{input}")).ok()
+            } else {
+                None
+            }
+        }
+    };
+
+    match url {
+        Some(url) => {
+            if let Some((_, redirect)) = url.query_pairs().find(|(k, _)| k == "redirectUrl") {
+                return get_post_id(&redirect);
+            }
+            get_post_id_from_path(url.path())
+        }
+        None => {
+            let path = input.split(['?', '#']).next().unwrap_or_default();
+            get_post_id_from_path(path)
+        }
+    }
+}
+
+/// Checks if `host` belongs to Medium, either on medium.com or on one of `custom_domains`.
+/// Ported from `isMedium` in libmedium.user.js
+pub fn is_medium_host(host: &str, custom_domains: &[String]) -> bool {
+    let is_domain = |domain: &str| {
+        host == domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.ends_with('.'))
+    };
+    is_domain("medium.com") || custom_domains.iter().any(|d| is_domain(d))
+}
+
+pub fn is_medium(url: &Url) -> bool {
+    url.host_str()
+        .is_some_and(|host| is_medium_host(host, &SETTINGS.custom_domains))
+}
+
+/// URLs that [crate::Data::resolve_post_id] may request: Medium and custom domains over
+/// HTTP(S) on default ports. Fetching anything else would let clients make the
+/// instance request internal services.
+pub fn is_resolvable(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https") && url.port().is_none() && is_medium(url)
+}
+
+/// Extract username from user profile URLs: `medium.com/@username` and `username.medium.com`
+pub fn get_username(url: &Url) -> Option<String> {
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+    let first = segments.next();
+    if let Some(username) = first.and_then(|s| s.strip_prefix('@')) {
+        if segments.next().is_none() && !username.is_empty() {
+            return Some(username.to_owned());
+        }
+        return None;
+    }
+
+    if first.is_none() {
+        let host = url.host_str()?;
+        if let Some(username) = host.strip_suffix(".medium.com") {
+            if !username.contains('.') && !RESERVED_SUBDOMAINS.contains(&username) {
+                return Some(username.to_owned());
+            }
+        }
+    }
+    None
+}
+
+/// Rewrite links to Medium posts and users so that they point to this instance.
+/// All other links are returned as is.
+pub fn rewrite_link(href: &str) -> String {
+    let url = match Url::parse(href) {
+        Ok(url) if is_medium(&url) => url,
+        _ => return href.to_owned(),
+    };
+
+    if let Some(post_id) = get_post_id(href) {
+        V1_API_ROUTES.proxy.get_by_post_id(&post_id)
+    } else if let Some(username) = get_username(&url) {
+        V1_API_ROUTES.proxy.get_author(&username)
+    } else {
+        href.to_owned()
+    }
+}
+
+/// Make paths on this instance absolute, for exported posts that are read elsewhere
+pub fn absolute(href: &str) -> String {
+    if href.starts_with('/') && !href.starts_with("//") {
+        format!("{}{href}", crate::SETTINGS.server.get_base_url())
+    } else {
+        href.to_owned()
+    }
+}
+
+/// Paths on this instance that are only served over HTTP
+const HTTP_ONLY: [&str; 3] = ["/asset/", "/media/", "/gist/"];
+
+/// Turn absolute URLs of posts and authors on this instance back into paths, for
+/// the Gemini and Gopher frontends. Assets are only served over HTTP and are left
+/// as they are.
+pub fn relative(href: &str) -> String {
+    match href.strip_prefix(&SETTINGS.server.get_base_url()) {
+        Some(path) if path.starts_with('/') && !HTTP_ONLY.iter().any(|p| path.starts_with(p)) => {
+            path.to_owned()
+        }
+        _ => href.to_owned(),
+    }
+}
+
+fn get_post_id_from_path(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    if let Some(pos) = segments.iter().position(|s| *s == "p") {
+        if let Some(id) = segments.get(pos + 1) {
+            if is_post_id(id) {
+                return Some(id.to_string());
+            }
+        }
+    }
+
+    segments
+        .iter()
+        .rev()
+        .filter_map(|s| s.split('-').next_back())
+        .find(|id| is_post_id(id))
+        .map(|id| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_id_works() {
+        const ID: &str = "7158b1cdd50c";
+        let urls = [
+            "7158b1cdd50c",
+            "fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "/fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "/tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "https://medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "https://medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c/",
+            "https://medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c?source=rss----5e5bef33608a---4",
+            "https://medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c#d3ad",
+            "https://medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c/responses",
+            "medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "https://tylerneely.medium.com/fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "https://medium.com/p/7158b1cdd50c",
+            "https://medium.com/p/7158b1cdd50c/responses/show",
+            "http://medium.com/p/7158b1cdd50c",
+            "https://levelup.gitconnected.com/fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "https://medium.com/some-publication/fear-and-loathing-in-lock-free-programming-7158b1cdd50c?source=collection_home---4------0-----------------------",
+            "https://medium.com/m/global-identity?redirectUrl=https%3A%2F%2Flevelup.gitconnected.com%2Ffear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+            "  https://medium.com/p/7158b1cdd50c  ",
+        ];
+
+        for url in urls.iter() {
+            assert_eq!(get_post_id(url).as_deref(), Some(ID), "{url}");
+        }
+
+        let urls = [
+            "",
+            "/",
+            "https://medium.com/",
+            "https://medium.com/@tylerneely",
+            "https://link.medium.com/Xyz12AbCdEf",
+            "https://medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming",
+            "top-10-things-20231",
+        ];
+
+        for url in urls.iter() {
+            assert_eq!(get_post_id(url), None, "{url}");
+        }
+    }
+
+    #[test]
+    fn is_medium_works() {
+        let custom_domains = vec!["itnext.io".to_string(), "blog.discord.com".to_string()];
+        let hosts = [
+            ("medium.com", true),
+            ("tylerneely.medium.com", true),
+            ("itnext.io", true),
+            ("www.itnext.io", true),
+            ("blog.discord.com", true),
+            ("discord.com", false),
+            ("notmedium.com", false),
+            ("medium.com.example.org", false),
+            ("github.com", false),
+        ];
+        for (host, is_medium) in hosts.iter() {
+            assert_eq!(is_medium_host(host, &custom_domains), *is_medium, "{host}");
+        }
+    }
+
+    #[test]
+    fn is_resolvable_works() {
+        let urls = [
+            ("https://link.medium.com/8eYjPzCVBnb", true),
+            ("https://medium.com/@tylerneely/fear-and-loathing", true),
+            ("https://tylerneely.medium.com/fear-and-loathing", true),
+            ("https://uxdesign.cc/some-post", true),
+            ("http://127.0.0.1/", false),
+            ("http://169.254.169.254/latest/meta-data/", false),
+            ("http://localhost/", false),
+            ("https://medium.com:8443/", false),
+            ("https://medium.com.evil.example/", false),
+            ("ftp://medium.com/", false),
+        ];
+        for (url, resolvable) in urls {
+            assert_eq!(
+                is_resolvable(&Url::parse(url).unwrap()),
+                resolvable,
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn rewrite_link_works() {
+        let links = [
+            (
+                "https://medium.com/@tylerneely/fear-and-loathing-in-lock-free-programming-7158b1cdd50c?source=rss",
+                "/utils/post/7158b1cdd50c",
+            ),
+            ("https://medium.com/p/7158b1cdd50c", "/utils/post/7158b1cdd50c"),
+            (
+                "https://levelup.gitconnected.com/fear-and-loathing-in-lock-free-programming-7158b1cdd50c",
+                "/utils/post/7158b1cdd50c",
+            ),
+            ("https://medium.com/@tylerneely", "/@tylerneely"),
+            ("https://tylerneely.medium.com/", "/@tylerneely"),
+            ("https://medium.com/about", "https://medium.com/about"),
+            ("https://help.medium.com/", "https://help.medium.com/"),
+            (
+                "https://github.com/spacejam/sled-7158b1cdd50c",
+                "https://github.com/spacejam/sled-7158b1cdd50c",
+            ),
+            ("#section", "#section"),
+        ];
+        for (link, rewritten) in links.iter() {
+            assert_eq!(&rewrite_link(link), rewritten, "{link}");
+        }
+    }
+}
@@ -16,12 +16,14 @@
  */
 use actix_web::web;
 
+use crate::api::routes::Api;
 use crate::meta::routes::Meta;
 use crate::proxy::routes::Proxy;
 
 pub const ROUTES: Routes = Routes::new();
 
 pub struct Routes {
+    pub api: Api,
     pub meta: Meta,
     pub proxy: Proxy,
 }
@@ -29,6 +31,7 @@ pub struct Routes {
 impl Routes {
     pub const fn new() -> Self {
         Self {
+            api: Api::new(),
             meta: Meta::new(),
             proxy: Proxy::new(),
         }
@@ -36,6 +39,7 @@ impl Routes {
 }
 
 pub fn services(cfg: &mut web::ServiceConfig) {
+    crate::api::services(cfg);
     crate::meta::services(cfg);
     crate::proxy::services(cfg);
 }
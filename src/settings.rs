@@ -30,6 +30,9 @@ pub struct Server {
     pub ip: String,
     pub proxy_has_tls: bool,
     pub workers: Option<usize>,
+    /// URL that readers use to reach the instance, when it differs from
+    /// `domain` and `port`(behind a reverse proxy, on a sub-path, etc.)
+    pub public_url: Option<String>,
 }
 
 impl Server {
@@ -37,6 +40,113 @@ impl Server {
     pub fn get_ip(&self) -> String {
         format!("{}:{}", self.ip, self.port)
     }
+
+    /// Public URL of the instance, without a trailing slash. Instances behind a
+    /// TLS proxy are assumed to be served on the default HTTPS port.
+    pub fn get_base_url(&self) -> String {
+        if let Some(url) = &self.public_url {
+            return url.trim_end_matches('/').to_owned();
+        }
+        match (self.proxy_has_tls, self.port) {
+            (true, _) => format!("https://{}", self.domain),
+            (false, 80) => format!("http://{}", self.domain),
+            (false, port) => format!("http://{}:{port}", self.domain),
+        }
+    }
+}
+
+/// Gemini listener, served alongside HTTP when configured
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gemini {
+    #[serde(default = "Gemini::default_port")]
+    pub port: u32,
+    pub ip: String,
+    /// PEM encoded certificate chain. Gemini clients trust certificates on first
+    /// use, so a self-signed certificate is fine.
+    pub cert: String,
+    /// PEM encoded PKCS #8 private key
+    pub key: String,
+}
+
+impl Gemini {
+    fn default_port() -> u32 {
+        1965
+    }
+
+    #[cfg(not(tarpaulin_include))]
+    pub fn get_ip(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// Gopher listener, served alongside HTTP when configured. Menus point to
+/// `server.domain` and `port`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gopher {
+    #[serde(default = "Gopher::default_port")]
+    pub port: u32,
+    pub ip: String,
+}
+
+impl Gopher {
+    pub fn default_port() -> u32 {
+        70
+    }
+
+    #[cfg(not(tarpaulin_include))]
+    pub fn get_ip(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// Privacy front-ends that third-party embeds are rewritten to
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Embeds {
+    /// Base URL of an Invidious instance, used for YouTube embeds
+    pub invidious: Option<String>,
+    /// Base URL of a Nitter instance, used for tweets
+    pub nitter: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GitHub {
+    /// Personal access token, used to fetch gists. Anonymous requests are limited
+    /// to 60 requests per hour.
+    pub token: Option<String>,
+}
+
+/// Syntax highlighting themes, names of themes in syntect's default theme set
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyntaxThemes {
+    pub light: String,
+    pub dark: String,
+}
+
+impl Default for SyntaxThemes {
+    fn default() -> Self {
+        Self {
+            light: "InspiredGitHub".into(),
+            dark: "base16-ocean.dark".into(),
+        }
+    }
+}
+
+/// Syntax to use for files with a given extension, for extensions that are shared
+/// by more than one language
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyntaxOverride {
+    pub extension: String,
+    /// Syntax to use when none of the prefixes match
+    pub default: String,
+    /// Syntaxes to use when code starts with a prefix
+    #[serde(default)]
+    pub prefixes: Vec<SyntaxPrefix>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyntaxPrefix {
+    pub prefix: String,
+    pub syntax: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -45,6 +155,22 @@ pub struct Settings {
     pub cache: Option<String>,
     pub server: Server,
     pub source_code: String,
+    /// Publications that serve Medium posts from their own domains. Links to
+    /// these domains are rewritten to point to this instance.
+    #[serde(default)]
+    pub custom_domains: Vec<String>,
+    #[serde(default)]
+    pub embeds: Embeds,
+    #[serde(default)]
+    pub github: GitHub,
+    #[serde(default)]
+    pub syntax_themes: SyntaxThemes,
+    /// Directory with additional `.sublime-syntax` files
+    pub syntaxes_dir: Option<String>,
+    #[serde(default)]
+    pub syntax_overrides: Vec<SyntaxOverride>,
+    pub gemini: Option<Gemini>,
+    pub gopher: Option<Gopher>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -104,5 +230,36 @@ impl Settings {
     #[cfg(not(tarpaulin_include))]
     fn check_url(&self) {
         Url::parse(&self.source_code).expect("Please enter a URL for source_code in settings");
+        if let Some(url) = &self.server.public_url {
+            Url::parse(url).expect("Please enter a URL for server.public_url in settings");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_url_works() {
+        let mut server = Server {
+            port: 7000,
+            domain: "localhost".into(),
+            ip: "0.0.0.0".into(),
+            proxy_has_tls: false,
+            workers: None,
+            public_url: None,
+        };
+        assert_eq!(server.get_base_url(), "http://localhost:7000");
+
+        server.port = 80;
+        assert_eq!(server.get_base_url(), "http://localhost");
+
+        server.proxy_has_tls = true;
+        server.domain = "libmedium.example.com".into();
+        assert_eq!(server.get_base_url(), "https://libmedium.example.com");
+
+        server.public_url = Some("https://example.com/medium/".into());
+        assert_eq!(server.get_base_url(), "https://example.com/medium");
     }
 }
@@ -0,0 +1,135 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Plain text rendering of posts, for terminals and scripts
+//!
+//! Text is rendered from [PostDocument]s. Link targets follow the linked text in
+//! parentheses and code is indented.
+use crate::api::{Block, PostDocument, Span};
+
+const INDENT: &str = "    ";
+
+pub fn render(doc: &PostDocument) -> String {
+    let byline = format!("{} · {}", doc.author.name, &doc.created_at[..10]);
+    let mut blocks = vec![
+        format!("{}\n{}", doc.title, "=".repeat(doc.title.chars().count())),
+        format!("{byline}\n{}", doc.url),
+    ];
+    for block in doc.blocks.iter() {
+        let text = match block {
+            Block::Heading { level, spans } => {
+                let heading = text(spans);
+                // underlined like Setext headings, posts use H3 for section titles
+                match level {
+                    1..=3 => format!("{heading}\n{}", "-".repeat(heading.chars().count())),
+                    _ => heading,
+                }
+            }
+            Block::Paragraph { spans } => text(spans),
+            Block::Quote { spans, .. } => indent(&text(spans), "  "),
+            Block::List { ordered, items } => items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    let marker = if *ordered {
+                        format!("{}. ", index + 1)
+                    } else {
+                        "- ".to_owned()
+                    };
+                    let item = indent(&text(item), &" ".repeat(marker.len()));
+                    format!("{marker}{}", item.trim_start())
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Block::Image { url, caption, .. } => match text(caption) {
+                caption if caption.is_empty() => format!("[Image: {url}]"),
+                caption => format!("[Image: {caption} ({url})]"),
+            },
+            Block::Code { code, .. } => indent(code.trim_end_matches('\n'), INDENT),
+            Block::CodeEmbed { url, host, files } => match files {
+                Some(files) => files
+                    .iter()
+                    .map(|file| {
+                        format!(
+                            "{}:\n\n{}",
+                            file.name,
+                            indent(file.content.trim_end_matches('\n'), INDENT)
+                        )
+                    })
+                    .chain(std::iter::once(format!("({url})")))
+                    .collect::<Vec<String>>()
+                    .join("\n\n"),
+                None => format!("[Code on {host}: {url}]"),
+            },
+            Block::Embed { url, title } => match title {
+                Some(title) => format!("[Embed: {title} ({url})]"),
+                None => format!("[Embed: {url}]"),
+            },
+            Block::Link { url, title, .. } => format!("[{title} ({url})]"),
+        };
+        blocks.push(text);
+    }
+    let mut text = blocks.join("\n\n");
+    text.push('\n');
+    text
+}
+
+fn text(spans: &[Span]) -> String {
+    let mut text = String::default();
+    let mut spans = spans.iter().peekable();
+    while let Some(span) = spans.next() {
+        text += &span.text;
+        // a link can span several spans with different formatting
+        if let Some(link) = &span.link {
+            if spans.peek().and_then(|next| next.link.as_ref()) != Some(link) {
+                text += &format!(" ({link})");
+            }
+        }
+    }
+    text
+}
+
+pub fn indent(text: &str, indent: &str) -> String {
+    text.lines()
+        .map(|line| match line {
+            "" => String::default(),
+            line => format!("{indent}{line}"),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_works() {
+        let post = crate::proxy::tests::post();
+        let doc = crate::api::document("7158b1cdd50c", &post, &[]);
+        let text = render(&doc);
+        assert!(text.starts_with(
+            "Fear and Loathing in Lock-Free Programming\n==========================================\n\nTyler Neely · 2017-09-27\n"
+        ));
+        assert!(text.contains("there are only two (http://www.1024cores.net/) people ("));
+        assert!(text.contains("Lock-Free 101\n-------------\n\n1. Create a node\n2. Read"));
+        assert!(text.contains(
+            "\n    fn cas(&self, old: usize, new: usize) -> usize {\n        self.0.compare_and_swap"
+        ));
+        assert!(text.contains("  Don’t write lock-free algorithms. You will die."));
+        assert!(text.contains("[Image: Spin until successful ("));
+    }
+}